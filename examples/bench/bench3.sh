@@ -0,0 +1,18 @@
+#!/bin/bash
+
+# grow the database to about 10000 pages, one blob row per page, and measure
+# the backup after every step: the slope is the per-page cost, the intercept
+# the fixed cost
+COUNTER=0
+while [ $COUNTER -lt 10 ];
+do
+  dfx canister call backend bench3_fill '(1000)'
+  dfx canister call backend bench3_backup
+  COUNTER=`expr $COUNTER + 1`
+done
+
+# restore performance counter with 1, 64 and 256 pages per stable64_write
+for BATCH in 1 64 256
+do
+  dfx canister call backend bench3_restore "(${BATCH})"
+done
@@ -5,6 +5,7 @@ extern crate serde;
 
 use ic_cdk::api::call::RejectionCode;
 use candid::CandidType;
+use ic_sqlite::backup;
 
 #[update]
 fn execute(sql: String) -> Result {
@@ -401,6 +402,63 @@ fn bench2_delete_person2_by_id(offset: usize) -> Result {
     }
 }
 
+#[update]
+fn bench3_fill(count: usize) -> Result {
+    let conn = ic_sqlite::CONN.lock().unwrap();
+    if let Err(err) = conn.execute(
+        "create table if not exists blobs ( id INTEGER PRIMARY KEY, data BLOB NOT NULL )",
+        []
+    ) {
+        return Err(Error::CanisterError {message: format!("bench3_fill: {:?}", err) });
+    }
+    // A 3500-byte blob fills most of a 4KB page, so every row adds about one page.
+    for _ in 0..count {
+        if let Err(err) = conn.execute("insert into blobs (data) values (zeroblob(3500));", []) {
+            return Err(Error::CanisterError {message: format!("bench3_fill: {:?}", err) });
+        }
+    }
+    match conn.query_row("PRAGMA page_count;", [], |row| row.get::<_, i64>(0)) {
+        Ok(page_count) => Ok(format!("bench3_fill page_count: {:?}", page_count)),
+        Err(err) => Err(Error::CanisterError {message: format!("bench3_fill: {:?}", err) })
+    }
+}
+
+#[update]
+fn bench3_restore(write_batch_pages: usize) -> Result {
+    let backup = match backup::db_backup_on_memory(&mut ic_sqlite::CONN.lock().unwrap()) {
+        Ok(backup) => backup,
+        Err(err) => return Err(Error::CanisterError {message: format!("restore: {}", err) })
+    };
+    let options = backup::RestoreOptions { write_batch_pages, verify_crc: false };
+    let start = ic_cdk::api::performance_counter(0);
+    match backup::db_restore_with_options(&backup, &options) {
+        Ok(_) => Ok(format!(
+            "restore {:?} pages, {:?} pages per write, performance_counter: {:?}",
+            backup.len() / 4096,
+            write_batch_pages,
+            ic_cdk::api::performance_counter(0) - start
+        )),
+        Err(err) => Err(Error::CanisterError {message: format!("restore: {}", err) })
+    }
+}
+
+#[query]
+fn bench3_backup() -> Result {
+    let estimate = match backup::estimate_backup_instructions() {
+        Ok(estimate) => estimate,
+        Err(err) => return Err(Error::CanisterError {message: format!("estimate: {}", err) })
+    };
+    match backup::measure_backup_instructions() {
+        Ok((backup, instructions)) => Ok(format!(
+            "backup {:?} pages, estimate: {:?}, performance_counter: {:?}",
+            backup.len() / 4096,
+            estimate,
+            instructions
+        )),
+        Err(err) => Err(Error::CanisterError {message: format!("backup: {}", err) })
+    }
+}
+
 #[derive(CandidType, Debug, Serialize, Deserialize, Default)]
 struct Person {
     id: u64,
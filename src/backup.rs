@@ -1,8 +1,427 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fmt;
 use std::io::{self, Read, Write};
+use std::os::raw::c_uint;
+use std::{ptr, slice};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use rusqlite::Connection;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use rusqlite::{ffi, params, Connection, OpenFlags};
+use sqlite_vfs::register;
 
-use crate::utils::read;
+use ic_cdk::api::{instruction_counter, time};
+
+use crate::query::{refresh_statement_cache, table_exists};
+use crate::utils::{self, read, set_size, write, HeapMemory, Memory, StableMemory};
+use crate::vfs::PagesVfs;
+use crate::{max_db_bytes, sqlite_to_io, CONN};
+
+/// Page size of the database, as set up when `CONN` is opened.
+const PAGE_SIZE: usize = 4096;
+
+/// Every SQLite database file starts with these 16 bytes.
+const SQLITE_HEADER_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// Magic bytes starting every [`Manifest`].
+const MANIFEST_MAGIC: &[u8; 8] = b"ICSQLITE";
+
+/// Version of the [`Manifest`] encoding.
+const MANIFEST_VERSION: u16 = 2;
+
+/// Size in bytes of an encoded [`Manifest`].
+///
+/// The layout is, with all integers big-endian: `[8 bytes magic "ICSQLITE"]
+/// [u16 version][u32 page_size][u64 page_count][i32 schema_version]
+/// [u64 timestamp_ns][32 bytes image_hash][u32 crc]`, where `crc` is the CRC-32
+/// of the preceding bytes.
+pub const MANIFEST_SIZE: usize = 70;
+
+/// Size of the `[u32 page_start][u16 page_count]` header of a framed chunk.
+const FRAME_HEADER_SIZE: usize = 6;
+
+/// Size of the trailing CRC-32 of a framed chunk.
+const FRAME_CRC_SIZE: usize = 4;
+
+/// Number of pages written to the VFS since the canister (re)started.
+static PAGES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Id handed out to the next [`BackupSession`].
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    /// Open backup sessions, kept across messages until closed.
+    static ref BACKUP_SESSIONS: Mutex<HashMap<u64, BackupSession>> = Mutex::new(HashMap::new());
+    /// Most recent maintenance events, oldest first.
+    static ref MAINTENANCE_LOG: Mutex<VecDeque<MaintenanceEvent>> = Mutex::new(VecDeque::new());
+    /// Heap memory behind the [`SELF_TEST_VFS`], registered on first use.
+    static ref SELF_TEST_MEMORY: Mutex<Arc<HeapMemory>> = {
+        let memory = Arc::new(HeapMemory::default());
+        register(SELF_TEST_VFS, PagesVfs::with_memory(memory.clone()), false).unwrap();
+        Mutex::new(memory)
+    };
+}
+
+/// Number of events kept by [`maintenance_log`].
+const MAINTENANCE_LOG_CAPACITY: usize = 32;
+
+/// Pages read by [`calibrate_backup_cost`] to measure the per-page cost.
+const CALIBRATION_PAGES: i64 = 16;
+
+/// Cost of the last [`calibrate_backup_cost`] run, if any.
+static BACKUP_COST: Mutex<Option<BackupCost>> = Mutex::new(None);
+
+/// Instructions spent by [`db_backup_on_memory`], as measured in this canister by
+/// [`calibrate_backup_cost`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupCost {
+    /// Fixed overhead of a backup: the transaction and the `page_count` query.
+    pub base: u64,
+    /// Cost of one 4KB page: the `stable64_read` system call plus copying the
+    /// page into the output buffer.
+    pub per_page: u64,
+}
+
+/// A baseline marker for counting page writes, see [`pages_written_since`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeToken(u64);
+
+/// A chunked download of the database spanning several canister messages.
+///
+/// Created by [`open_backup_session`] and looked up again in later messages with
+/// [`backup_session`], e.g. to serve a `(total, chunk(i))` style interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSession {
+    id: u64,
+    chunk_size: usize,
+    total_bytes: u64,
+    token: ChangeToken,
+}
+
+/// Iterator returned by [`pages`].
+struct Pages {
+    _conn: MutexGuard<'static, Connection>,
+    next: i64,
+    page_count: i64,
+}
+
+impl Iterator for Pages {
+    type Item = io::Result<(i64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.page_count {
+            return None;
+        }
+        let page_number = self.next;
+        self.next += 1;
+        Some(read_page_from_vfs(page_number, PAGE_SIZE).map(|page_data| (page_number, page_data)))
+    }
+}
+
+/// Progress of a backup driven by [`backup_incremental_step`].
+///
+/// Keep it in canister memory (e.g. a `thread_local!`) between the messages or
+/// timers driving the backup.
+#[derive(Debug, Default)]
+pub struct BackupState {
+    total: i64,
+    pages_done: i64,
+    token: Option<ChangeToken>,
+    buffer: Vec<u8>,
+}
+
+/// Outcome of a single [`backup_incremental_step`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum BackupStepResult {
+    /// More steps are needed; `pages_done` of `total` pages are copied so far.
+    InProgress { pages_done: i64, total: i64 },
+    /// The backup is complete.
+    Done(Vec<u8>),
+}
+
+/// Progress of a restore pulled in chunks, see [`restore_pull_init`].
+///
+/// Keep it in canister memory (e.g. a `thread_local!`) between the update calls
+/// feeding the chunks.
+#[derive(Debug)]
+pub struct RestorePull {
+    total_chunks: u32,
+    chunk_size: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    restored: bool,
+}
+
+impl RestorePull {
+    /// Number of chunks the backup is split into.
+    pub fn total_chunks(&self) -> u32 {
+        self.total_chunks
+    }
+
+    /// Number of distinct chunks received so far.
+    pub fn received_chunks(&self) -> u32 {
+        self.received
+    }
+
+    /// Indexes of the chunks still to be fed, e.g. to resume after an interruption.
+    pub fn missing_chunks(&self) -> Vec<u32> {
+        if self.restored {
+            return Vec::new();
+        }
+        (0..self.total_chunks)
+            .filter(|index| self.chunks[*index as usize].is_none())
+            .collect()
+    }
+
+    /// Returns `true` once every chunk arrived and the backup was restored.
+    pub fn is_complete(&self) -> bool {
+        self.restored
+    }
+}
+
+/// Tuning for [`db_restore_with_options`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestoreOptions {
+    /// Pages copied into stable memory per `stable64_write` call. `0` is treated
+    /// as `1`.
+    ///
+    /// Every write also checks the memory size and updates the size record, so a
+    /// restore of 10,000 pages makes 60,004 stable memory system calls with 1
+    /// page per write, 946 with 64 and 244 with 256. The pages are written
+    /// straight from the backup either way, so the bytes copied stay the same.
+    /// `bench3.sh` in `examples/bench` measures the instructions on a replica.
+    pub write_batch_pages: usize,
+    /// Reads every batch back after writing it and compares CRC-32 checksums,
+    /// using a heap buffer of one batch.
+    pub verify_crc: bool,
+}
+
+impl Default for RestoreOptions {
+    /// Batches of 64 pages (256 KiB) without read-back verification. Larger
+    /// batches save few further calls, but grow the `verify_crc` buffer.
+    fn default() -> Self {
+        Self {
+            write_batch_pages: 64,
+            verify_crc: false,
+        }
+    }
+}
+
+/// Steps run by [`compact_and_backup`] before and after taking the backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceOptions {
+    /// Runs `PRAGMA wal_checkpoint(TRUNCATE)`. A no-op unless the database is in
+    /// WAL mode, which the stable-memory VFS doesn't support yet.
+    pub checkpoint: bool,
+    /// Runs `PRAGMA optimize` to refresh the query planner statistics.
+    pub optimize: bool,
+    /// Runs `VACUUM` to drop free pages from the database, and thus the backup.
+    pub vacuum: bool,
+    /// Opens the finished backup with [`dry_run_restore`] and fails unless its
+    /// integrity check is clean. With `with_manifest`, the image is also checked
+    /// against the manifest's hash.
+    pub verify: bool,
+    /// Prefixes the backup with a [`Manifest`] holding a hash of the image, see
+    /// [`db_backup_with_manifest`].
+    pub with_manifest: bool,
+}
+
+impl Default for MaintenanceOptions {
+    /// Every step except `vacuum`, which rewrites the whole database.
+    fn default() -> Self {
+        Self {
+            checkpoint: true,
+            optimize: true,
+            vacuum: false,
+            verify: true,
+            with_manifest: true,
+        }
+    }
+}
+
+/// A maintenance step run by [`compact_and_backup`], see [`maintenance_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceAction {
+    /// A WAL checkpoint moved `frames` frames into the database.
+    Checkpoint { frames: i64 },
+    /// `PRAGMA optimize` ran.
+    Optimize,
+    /// `VACUUM` shrank the database by `pages_reclaimed` pages.
+    Vacuum { pages_reclaimed: i64 },
+    /// A backup of `bytes` bytes was produced.
+    Backup { bytes: u64 },
+}
+
+/// An entry of the [`maintenance_log`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceEvent {
+    /// IC time the step finished, in nanoseconds since the Unix epoch.
+    pub timestamp_ns: u64,
+    /// What the step did.
+    pub action: MaintenanceAction,
+}
+
+/// Metadata stored uncompressed in front of a backup by [`db_backup_with_manifest`].
+///
+/// It is encoded in the first [`MANIFEST_SIZE`] bytes, so [`peek_backup_info`] can
+/// read it without downloading or decoding the rest of the backup. Its own bytes
+/// are covered by a CRC-32, and the image behind it by `image_hash`, which
+/// [`verify_backup_with_manifest`] checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Manifest {
+    /// Page size of the database in bytes.
+    pub page_size: u32,
+    /// Number of pages in the backup.
+    pub page_count: u64,
+    /// `PRAGMA schema_version` at backup time.
+    pub schema_version: i32,
+    /// IC time of the backup, in nanoseconds since the Unix epoch.
+    pub timestamp_ns: u64,
+    /// [`backup_content_hash`] of the image following the manifest.
+    pub image_hash: [u8; 32],
+}
+
+/// Errors returned by the backup and restore APIs.
+#[derive(Debug)]
+pub enum BackupError {
+    /// Reading or writing stable memory failed.
+    Io(io::Error),
+    /// An underlying SQLite call failed.
+    ///
+    /// Errors raised by rusqlite itself rather than by SQLite (e.g. a failed type
+    /// conversion) are reported with `SQLITE_ERROR` as both codes.
+    Sqlite {
+        /// Primary result code, e.g. `SQLITE_IOERR` (10).
+        code: i32,
+        /// Extended result code, e.g. `SQLITE_IOERR_SHORT_READ` (522).
+        extended_code: i32,
+        /// Error message reported by SQLite.
+        message: String,
+    },
+    /// The backup data is not a usable database image.
+    InvalidImage(String),
+    /// The database has no pages, so there is nothing to back up or restore.
+    EmptyDatabase,
+    /// The checksum of a framed chunk doesn't match its contents.
+    ChecksumMismatch {
+        /// Checksum stored in the chunk.
+        expected: u32,
+        /// Checksum computed over the received data.
+        actual: u32,
+    },
+    /// Tables expected by [`assert_schema`] are missing from the database.
+    MissingTables(Vec<String>),
+    /// The database would grow past the limit set by [`set_max_db_bytes`](crate::set_max_db_bytes).
+    QuotaExceeded {
+        /// The configured limit in bytes.
+        limit: u64,
+    },
+    /// The backup was made with a different page size than the VFS uses.
+    PageSizeMismatch {
+        /// Page size stored in the backup's SQLite header.
+        backup: u32,
+        /// Page size of the stable-memory VFS.
+        expected: u32,
+    },
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+            Self::Sqlite {
+                code,
+                extended_code,
+                message,
+            } => write!(
+                f,
+                "SQLite error: {} (code {}, extended code {})",
+                message, code, extended_code
+            ),
+            Self::InvalidImage(reason) => write!(f, "invalid backup image: {}", reason),
+            Self::EmptyDatabase => f.write_str("database is empty"),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:#010x}, got {:#010x}",
+                expected, actual
+            ),
+            Self::MissingTables(tables) => write!(f, "missing tables: {}", tables.join(", ")),
+            Self::QuotaExceeded { limit } => write!(f, "database size limit of {} bytes exceeded", limit),
+            Self::PageSizeMismatch { backup, expected } => write!(
+                f,
+                "backup uses {}-byte pages, expected {}-byte pages",
+                backup, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Sqlite { .. }
+            | Self::InvalidImage(_)
+            | Self::EmptyDatabase
+            | Self::ChecksumMismatch { .. }
+            | Self::MissingTables(_)
+            | Self::QuotaExceeded { .. }
+            | Self::PageSizeMismatch { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(failure, _)
+                if failure.code == ffi::ErrorCode::DiskFull && max_db_bytes().is_some() =>
+            {
+                Self::QuotaExceeded {
+                    limit: max_db_bytes().unwrap(),
+                }
+            }
+            rusqlite::Error::SqliteFailure(failure, message) => Self::Sqlite {
+                code: failure.extended_code & 0xff,
+                extended_code: failure.extended_code,
+                message: message.unwrap_or_else(|| failure.to_string()),
+            },
+            err => Self::Sqlite {
+                code: ffi::SQLITE_ERROR,
+                extended_code: ffi::SQLITE_ERROR,
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Outcome of [`dry_run_restore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// Number of tables in the backup's schema.
+    pub table_count: i64,
+    /// Number of pages in the backup.
+    pub page_count: i64,
+    /// The backup's `PRAGMA schema_version`.
+    pub schema_version: i32,
+    /// Rows returned by `PRAGMA integrity_check`, `["ok"]` for a healthy database.
+    pub integrity_check: Vec<String>,
+}
+
+impl RestoreReport {
+    /// Returns `true` if the integrity check found no problems.
+    pub fn is_clean(&self) -> bool {
+        self.integrity_check.len() == 1 && self.integrity_check[0] == "ok"
+    }
+}
 
 /// Streams a backup of the database as uncompressed data.
 ///
@@ -14,6 +433,9 @@ use crate::utils::read;
 /// to high memory usage or out-of-memory errors. It is particularly useful for
 /// cases where you need to stream the database backup without compressing it.
 ///
+/// Only the `main` schema is backed up. Databases added with `ATTACH` are not
+/// included; back them up separately with [`db_backup_schema`].
+///
 /// # Returns
 ///
 /// Returns an `io::Result<impl Read>` where the `impl Read` is an `io::Cursor` over a
@@ -23,23 +445,19 @@ use crate::utils::read;
 /// # Errors
 ///
 /// This function returns an `io::Error` if:
+/// - The database has no pages yet, with a [`BackupError::EmptyDatabase`] inside.
 /// - The database connection is not properly initialized or locked.
 /// - The transaction cannot be started or committed.
 /// - Page data cannot be read from the virtual file system or written to the buffer.
 ///
-/// # Panics
-///
-/// This function may panic if:
-/// - The transaction cannot be started or committed.
-/// - Page data cannot be read or written to the buffer.
-///
 /// # Example
 ///
-/// ```
+/// ```no_run
 /// use std::io::Read;
-/// use ic_sqlite_features::stream_db_backup;
+/// use ic_sqlite_features::{backup::stream_db_backup, CONN};
 ///
-/// let mut backup_stream = stream_db_backup().expect("Failed to create backup stream");
+/// let mut conn = CONN.lock().unwrap();
+/// let mut backup_stream = stream_db_backup(&mut conn).expect("Failed to create backup stream");
 /// let mut buffer = Vec::new();
 /// backup_stream.read_to_end(&mut buffer).expect("Failed to read backup data");
 /// // Use `buffer` as needed
@@ -47,22 +465,25 @@ use crate::utils::read;
 pub fn stream_db_backup(conn: &mut Connection) -> Result<impl Read, io::Error> {
 
     // Begin a transaction to ensure consistency
-    let tx = conn.transaction().unwrap();
+    let tx = conn.transaction().map_err(sqlite_to_io)?;
 
     // Create a buffer to store uncompressed data
     let mut buffer = Vec::new();
 
     let page_count: i64 = tx
         .query_row("PRAGMA page_count;", [], |row| row.get(0))
-        .unwrap();
+        .map_err(sqlite_to_io)?;
+    if page_count == 0 {
+        return Err(io::Error::other(BackupError::EmptyDatabase));
+    }
 
     for page_number in 1..=page_count {
-        let page_data = read_page_from_vfs(page_number, 4096)?;
+        let page_data = read_page_from_vfs(page_number, PAGE_SIZE)?;
         buffer.write_all(&page_data)?;
     }
 
     // Commit the transaction
-    tx.commit().unwrap();
+    tx.commit().map_err(sqlite_to_io)?;
 
     // Return the uncompressed data as a cursor for streaming
     Ok(io::Cursor::new(buffer))
@@ -75,50 +496,2113 @@ pub fn stream_db_backup(conn: &mut Connection) -> Result<impl Read, io::Error> {
 /// all at once, which may not be feasible for very large databases and could lead
 /// to high memory usage or out-of-memory errors.
 ///
+/// Only the `main` schema is backed up. Databases added with `ATTACH` are not
+/// included; back them up separately with [`db_backup_schema`].
+///
 /// # Returns
 ///
 /// Returns a `Vec<u8>` containing the entire database's raw data. The data is
 /// concatenated from each page of the database.
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function may panic if:
-/// - The database connection is not properly initialized or locked.
+/// This function returns a `BackupError` if:
+/// - The database has no pages yet (`BackupError::EmptyDatabase`), since such an
+///   image couldn't be restored meaningfully.
 /// - The transaction cannot be started or committed.
 /// - Page data cannot be read from the virtual file system.
 ///
+/// # Panics
+///
+/// This function may panic if the database connection is not properly initialized.
+///
 /// # Example
 ///
-/// ```
-/// use ic_sqlite_features::backup::db_backup_on_memory;
-/// let backup_data = db_backup_on_memory();
+/// ```no_run
+/// use ic_sqlite_features::{backup::db_backup_on_memory, CONN};
+///
+/// let mut conn = CONN.lock().unwrap();
+/// let backup_data = db_backup_on_memory(&mut conn).expect("Failed to back up");
 /// // Use `backup_data` as needed.
 /// ```
-pub fn db_backup_on_memory(conn: &mut Connection) -> Vec<u8> {
+pub fn db_backup_on_memory(conn: &mut Connection) -> Result<Vec<u8>, BackupError> {
 
     let mut output = Vec::new();
 
-    let tx = conn.transaction().unwrap();
+    let tx = conn.transaction()?;
 
-    let page_count: i64 = tx
-        .query_row("PRAGMA page_count;", [], |row| row.get(0))
-        .unwrap();
+    let page_count: i64 = tx.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    if page_count == 0 {
+        return Err(BackupError::EmptyDatabase);
+    }
+    read_image(&StableMemory, page_count, &mut output)?;
+
+    tx.commit()?;
+
+    Ok(output)
+}
+
+/// Appends the first `page_count` pages of the image in `mem` to `output`.
+fn read_image(mem: &dyn Memory, page_count: i64, output: &mut Vec<u8>) -> io::Result<()> {
+    output.reserve(page_count as usize * PAGE_SIZE);
     for page_number in 1..=page_count {
-        let page_data = read_page_from_vfs(page_number, 4096).unwrap();
+        let page_data = read_page(mem, page_number, PAGE_SIZE)?;
         output.extend_from_slice(&page_data);
     }
+    Ok(())
+}
+
+/// Restores a backup made by [`db_backup_on_memory`] into stable memory.
+///
+/// The live database is overwritten with the [`RestoreOptions::default`] batches
+/// and its recorded size is set to the size of the image, so the backup fully
+/// replaces the current content.
+///
+/// The image is checked and stable memory is grown to hold all of it before the
+/// first page is written, so an error leaves the live database as it was. Once
+/// the writes have started they can only be cut short by a trap, which rolls back
+/// the whole message.
+///
+/// `CONN` is reopened afterwards, as SQLite would otherwise keep the schema and
+/// pages it read from the old database, see [`invalidate_page_cache`]. Functions
+/// and collations registered on it are gone then, so register them again.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if:
+/// - `CONN` has a transaction open (`ErrorKind::WouldBlock`), which reopening it
+///   would roll back over the restored pages.
+/// - `data` holds no pages (`BackupError::EmptyDatabase`).
+/// - `data` is not a whole number of pages or doesn't start with an SQLite header.
+/// - The page size in the SQLite header isn't the VFS's 4096 bytes
+///   (`BackupError::PageSizeMismatch`).
+/// - `data` is larger than the limit set by [`set_max_db_bytes`](crate::set_max_db_bytes)
+///   (`BackupError::QuotaExceeded`).
+/// - Page data cannot be written to the virtual file system.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::{db_restore, BackupError};
+///
+/// assert!(matches!(db_restore(&[]), Err(BackupError::EmptyDatabase)));
+///
+/// // An image made with 8192-byte pages.
+/// let mut image = vec![0u8; 8192];
+/// image[..16].copy_from_slice(b"SQLite format 3\0");
+/// image[16..18].copy_from_slice(&8192u16.to_be_bytes());
+/// assert!(matches!(
+///     db_restore(&image),
+///     Err(BackupError::PageSizeMismatch { backup: 8192, expected: 4096 })
+/// ));
+/// ```
+pub fn db_restore(data: &[u8]) -> Result<(), BackupError> {
+    db_restore_with_options(data, &RestoreOptions::default())
+}
+
+/// Restores a backup like [`db_restore`], tuned by `options`.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as [`db_restore`],
+/// and `BackupError::ChecksumMismatch` if [`RestoreOptions::verify_crc`] is set
+/// and a batch reads back differently than it was written.
+///
+/// A checksum mismatch is only found after the batch was written, so the live
+/// database is then partially overwritten. Trap (e.g. with `ic_cdk::trap`) on
+/// this error to roll the message back and keep the old database.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{db_restore_with_options, RestoreOptions};
+///
+/// # let backup_data: Vec<u8> = Vec::new();
+/// let options = RestoreOptions { write_batch_pages: 256, verify_crc: true };
+/// db_restore_with_options(&backup_data, &options).expect("Failed to restore");
+/// ```
+pub fn db_restore_with_options(data: &[u8], options: &RestoreOptions) -> Result<(), BackupError> {
+    restore_parts(&[data], options)
+}
+
+/// Restores the image made of `parts`, in order, into stable memory.
+fn restore_parts(parts: &[&[u8]], options: &RestoreOptions) -> Result<(), BackupError> {
+    validate_parts(parts)?;
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    check_quota(len as u64)?;
+
+    let mut conn = CONN.lock().unwrap();
+    check_autocommit(&conn)?;
+    let restored = write_image(&StableMemory, parts, options);
+    record_page_writes((len / PAGE_SIZE) as u64);
+    restored?;
+    reopen(&mut conn)?;
+
+    Ok(())
+}
+
+/// Writes the validated image made of `parts`, in order, over the image in `mem`
+/// and sets its size.
+///
+/// `mem` is grown for the whole image before the first write, so running out of
+/// memory fails without changing anything.
+fn write_image(mem: &dyn Memory, parts: &[&[u8]], options: &RestoreOptions) -> Result<(), BackupError> {
+    let len: u64 = parts.iter().map(|part| part.len() as u64).sum();
+    utils::reserve(mem, len)?;
+
+    let batch_size = options.write_batch_pages.max(1) * PAGE_SIZE;
+    let mut offset = 0;
+    for batch in parts.iter().flat_map(|part| part.chunks(batch_size)) {
+        write(mem, batch, offset)?;
+
+        if options.verify_crc {
+            let mut written = vec![0u8; batch.len()];
+            read(mem, &mut written, offset)?;
+            let expected = crc32fast::hash(batch);
+            let actual = crc32fast::hash(&written);
+            if expected != actual {
+                return Err(BackupError::ChecksumMismatch { expected, actual });
+            }
+        }
+        offset += batch.len() as u64;
+    }
+    set_size(mem, len);
 
-    tx.commit().unwrap();
+    Ok(())
+}
 
-    output
+/// Checks that `data` looks like a whole SQLite image before it gets restored.
+fn validate_image(data: &[u8]) -> Result<(), BackupError> {
+    validate_parts(&[data])
 }
 
-pub fn read_page_from_vfs(page_number: i64, page_size: usize) -> Result<Vec<u8>, io::Error> {
-    let offset = (page_number - 1) * page_size as i64;
+/// Checks the image made of `parts` like [`validate_image`], without joining them.
+fn validate_parts(parts: &[&[u8]]) -> Result<(), BackupError> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    // Only the first 18 bytes are looked at, which may span several parts.
+    let mut data = Vec::with_capacity(18);
+    for part in parts {
+        data.extend_from_slice(&part[..part.len().min(18 - data.len())]);
+    }
 
-    let mut buffer = vec![0u8; page_size];
+    if len == 0 {
+        return Err(BackupError::EmptyDatabase);
+    }
+    if data.len() >= 18 && data.starts_with(SQLITE_HEADER_MAGIC) {
+        // Stored big-endian at offset 16, with 1 standing for 65536.
+        let backup = match u16::from_be_bytes([data[16], data[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if backup != PAGE_SIZE as u32 {
+            return Err(BackupError::PageSizeMismatch {
+                backup,
+                expected: PAGE_SIZE as u32,
+            });
+        }
+    }
+    if !len.is_multiple_of(PAGE_SIZE) {
+        return Err(BackupError::InvalidImage(format!(
+            "backup of {} bytes is not a multiple of the {}-byte page size",
+            len,
+            PAGE_SIZE
+        )));
+    }
+    if !data.starts_with(SQLITE_HEADER_MAGIC) {
+        return Err(BackupError::InvalidImage(
+            "backup doesn't start with an SQLite header".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-    let _ = read(&mut buffer, offset as u64)?;
+/// Fails with `QuotaExceeded` if an image of `len` bytes is over [`max_db_bytes`].
+fn check_quota(len: u64) -> Result<(), BackupError> {
+    match max_db_bytes() {
+        Some(limit) if len > limit => Err(BackupError::QuotaExceeded { limit }),
+        _ => Ok(()),
+    }
+}
 
-    Result::Ok(buffer)
-}
\ No newline at end of file
+/// Byte ranges of the SQLite header that change on every write, see [`normalize_backup`].
+const VOLATILE_HEADER_RANGES: [(usize, usize); 2] = [(24, 28), (92, 96)];
+
+/// Zeroes the header fields of a database image that change on every write.
+///
+/// Two big-endian `u32` fields of the 100-byte SQLite header are normalized:
+/// - bytes 24..28, the file change counter, bumped by every write transaction;
+/// - bytes 92..96, the version-valid-for number, set to the change counter when
+///   the header page count was last updated.
+///
+/// All other bytes are left alone, so two backups of the same content compare
+/// equal byte for byte after normalizing. The result is still a valid SQLite
+/// file: both fields end up equal (zero), which is all SQLite checks before
+/// trusting the page count in the header, and the counter simply restarts from
+/// zero with the next write. Images shorter than the header are left unchanged.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::normalize_backup;
+///
+/// let mut image = vec![0xAB; 4096];
+/// normalize_backup(&mut image);
+/// assert_eq!(&image[24..28], &[0, 0, 0, 0]);
+/// assert_eq!(&image[92..96], &[0, 0, 0, 0]);
+/// assert_eq!(image[28], 0xAB);
+/// ```
+pub fn normalize_backup(image: &mut [u8]) {
+    if image.len() < 100 {
+        return;
+    }
+    for (start, end) in VOLATILE_HEADER_RANGES {
+        image[start..end].fill(0);
+    }
+}
+
+/// Returns the SHA-256 hash of a database image, ignoring its volatile header fields.
+///
+/// The hash is computed as if the image had gone through [`normalize_backup`],
+/// without modifying it, so backups of identical content get identical hashes.
+/// Use it as the key of content-addressed backup storage.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::backup_content_hash;
+///
+/// let mut a = vec![0u8; 4096];
+/// let mut b = a.clone();
+/// a[24..28].copy_from_slice(&7u32.to_be_bytes());
+/// b[24..28].copy_from_slice(&8u32.to_be_bytes());
+/// assert_eq!(backup_content_hash(&a), backup_content_hash(&b));
+/// ```
+pub fn backup_content_hash(image: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    if image.len() < 100 {
+        hasher.update(image);
+    } else {
+        let mut header = [0u8; 100];
+        header.copy_from_slice(&image[..100]);
+        normalize_backup(&mut header);
+        hasher.update(header);
+        hasher.update(&image[100..]);
+    }
+    hasher.finalize().into()
+}
+
+/// Streams a backup and fails if any page was written while it was copied.
+///
+/// This costs as much as [`db_backup_on_memory`]: the copy is synchronous and no
+/// other message runs during it. The difference is that `CONN` is only locked to
+/// read the page count and take a [`ChangeToken`], so `out` may use `CONN`
+/// itself. If a page was written during the copy (by `out`, as nothing else can
+/// run), the image may mix old and new pages and the backup fails with
+/// `ErrorKind::Interrupted` instead of returning it.
+///
+/// # Returns
+///
+/// Returns the number of bytes written to `out`.
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if:
+/// - `CONN` has a transaction open (`ErrorKind::WouldBlock`), as its pages may
+///   not be committed yet.
+/// - The database has no pages yet, with a [`BackupError::EmptyDatabase`] inside.
+/// - A page was written while copying (`ErrorKind::Interrupted`).
+/// - The page count cannot be queried, or page data cannot be read from the
+///   virtual file system or written to `out`.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::backup_detecting_writes;
+///
+/// let mut buffer = Vec::new();
+/// let written = backup_detecting_writes(&mut buffer).expect("Failed to back up");
+/// ```
+pub fn backup_detecting_writes(out: &mut impl Write) -> io::Result<u64> {
+    let (page_count, token) = {
+        let conn = CONN.lock().unwrap();
+        if !conn.is_autocommit() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "a transaction is open on the connection",
+            ));
+        }
+        let page_count: i64 = conn
+            .query_row("PRAGMA page_count;", [], |row| row.get(0))
+            .map_err(sqlite_to_io)?;
+        if page_count == 0 {
+            return Err(io::Error::other(BackupError::EmptyDatabase));
+        }
+        (page_count, change_token())
+    };
+
+    let mut written = 0;
+    for page_number in 1..=page_count {
+        let page_data = read_page_from_vfs(page_number, PAGE_SIZE)?;
+        out.write_all(&page_data)?;
+        written += page_data.len() as u64;
+    }
+
+    let changed = pages_written_since(token);
+    if changed > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("{} pages were written during the backup", changed),
+        ));
+    }
+
+    Ok(written)
+}
+
+/// Lists the databases of the connection as reported by `PRAGMA database_list`.
+///
+/// # Returns
+///
+/// Returns `(name, file)` pairs, starting with `main`. The file is `None` for
+/// in-memory and temporary databases.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::list_attached_databases;
+///
+/// for (name, file) in list_attached_databases().unwrap() {
+///     // ...
+/// }
+/// ```
+pub fn list_attached_databases() -> rusqlite::Result<Vec<(String, Option<String>)>> {
+    let conn = CONN.lock().unwrap();
+    let mut stmt = conn.prepare("PRAGMA database_list;")?;
+    let databases = stmt
+        .query_map([], |row| {
+            let file: Option<String> = row.get(2)?;
+            Ok((row.get(1)?, file.filter(|file| !file.is_empty())))
+        })?
+        .collect();
+    databases
+}
+
+/// Performs a backup of a single schema, such as an attached database.
+///
+/// `"main"` is backed up from stable memory just like [`db_backup_on_memory`].
+/// Any other schema doesn't live in the VFS, so its pages are copied out by
+/// SQLite itself (`sqlite3_serialize`).
+///
+/// # Returns
+///
+/// Returns the raw database image of the schema.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if the schema doesn't exist or its
+/// pages cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{backup::db_backup_schema, CONN};
+///
+/// CONN.lock().unwrap().execute_batch("ATTACH ':memory:' AS aux;").unwrap();
+/// let aux_backup = db_backup_schema("aux").expect("Failed to back up `aux`");
+/// ```
+pub fn db_backup_schema(schema: &str) -> Result<Vec<u8>, BackupError> {
+    let mut conn = CONN.lock().unwrap();
+    if schema == "main" {
+        return db_backup_on_memory(&mut conn);
+    }
+
+    serialize_schema(&conn, schema)
+}
+
+/// Copies the pages of `schema` out of `conn` with `sqlite3_serialize`.
+fn serialize_schema(conn: &Connection, schema: &str) -> Result<Vec<u8>, BackupError> {
+    let name = CString::new(schema)
+        .map_err(|_| BackupError::InvalidImage(format!("invalid schema name `{}`", schema.escape_debug())))?;
+    let mut size: i64 = 0;
+    // SAFETY: the serialized copy is owned by us and released with `sqlite3_free`
+    // right after copying it into a `Vec`.
+    let data = unsafe {
+        let image = ffi::sqlite3_serialize(conn.handle(), name.as_ptr(), &mut size, 0);
+        if image.is_null() {
+            None
+        } else {
+            let data = slice::from_raw_parts(image, size as usize).to_vec();
+            ffi::sqlite3_free(image.cast());
+            Some(data)
+        }
+    };
+
+    data.ok_or_else(|| BackupError::InvalidImage(format!("no database named `{}`", schema)))
+}
+
+/// Measures what a [`db_backup_on_memory`] call costs in this canister.
+///
+/// Runs the fixed part of a backup and copies the first 16 pages the way a
+/// backup does, reading the instruction counter in between, so the cost reflects
+/// the replica and build the canister actually runs on. The result is kept for
+/// [`estimate_backup_instructions`] until the next call or upgrade.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as
+/// [`db_backup_on_memory`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::calibrate_backup_cost;
+///
+/// let cost = calibrate_backup_cost().expect("Failed to calibrate");
+/// ```
+pub fn calibrate_backup_cost() -> Result<BackupCost, BackupError> {
+    let mut conn = CONN.lock().unwrap();
+
+    let start = instruction_counter();
+    let tx = conn.transaction()?;
+    let page_count: i64 = tx.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    tx.commit()?;
+    let queried = instruction_counter();
+    if page_count == 0 {
+        return Err(BackupError::EmptyDatabase);
+    }
+
+    let pages = page_count.min(CALIBRATION_PAGES);
+    let mut output = Vec::new();
+    read_image(&StableMemory, pages, &mut output)?;
+    let copied = instruction_counter();
+
+    let cost = BackupCost {
+        base: queried.saturating_sub(start),
+        per_page: copied.saturating_sub(queried).div_ceil(pages as u64),
+    };
+    *BACKUP_COST.lock().unwrap() = Some(cost);
+    Ok(cost)
+}
+
+/// Estimates the number of instructions a [`db_backup_on_memory`] call would consume.
+///
+/// The estimate is `base + page_count * per_page` of the [`BackupCost`] measured
+/// by [`calibrate_backup_cost`], which runs on the first call after every upgrade.
+/// Later calls only run a `PRAGMA page_count`, so it is cheap enough to call
+/// before scheduling a backup on a timer to check it fits the message
+/// instruction limit.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if calibrating fails or the page count
+/// cannot be queried.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::estimate_backup_instructions;
+///
+/// let instructions = estimate_backup_instructions().expect("Failed to estimate");
+/// ```
+pub fn estimate_backup_instructions() -> Result<u64, BackupError> {
+    let cached = *BACKUP_COST.lock().unwrap();
+    let cost = match cached {
+        Some(cost) => cost,
+        None => calibrate_backup_cost()?,
+    };
+
+    let conn = CONN.lock().unwrap();
+    let page_count: i64 = conn.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    Ok(cost.base + page_count as u64 * cost.per_page)
+}
+
+/// Runs [`db_backup_on_memory`] and reports the instructions it consumed.
+///
+/// The instruction counter is read right before and after the backup, so the
+/// returned count excludes the time spent waiting for the `CONN` lock.
+///
+/// # Returns
+///
+/// Returns the backup data together with the number of instructions spent
+/// producing it.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as
+/// [`db_backup_on_memory`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::measure_backup_instructions;
+///
+/// let (backup_data, instructions) = measure_backup_instructions().expect("Failed to back up");
+/// ```
+pub fn measure_backup_instructions() -> Result<(Vec<u8>, u64), BackupError> {
+    let mut conn = CONN.lock().unwrap();
+
+    let start = instruction_counter();
+    let backup = db_backup_on_memory(&mut conn)?;
+    let spent = instruction_counter().saturating_sub(start);
+
+    Ok((backup, spent))
+}
+
+/// Reads a single page straight from the stable-memory VFS.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidInput` if `page_number` is below 1, and of
+/// kind `UnexpectedEof` if the page reaches past the end of the allocated stable
+/// memory, rather than a page padded with zeros.
+pub fn read_page_from_vfs(page_number: i64, page_size: usize) -> Result<Vec<u8>, io::Error> {
+    read_page(&StableMemory, page_number, page_size)
+}
+
+fn read_page(mem: &dyn Memory, page_number: i64, page_size: usize) -> Result<Vec<u8>, io::Error> {
+    if page_number < 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid page number {}", page_number),
+        ));
+    }
+    let offset = (page_number as u64 - 1)
+        .checked_mul(page_size as u64)
+        .ok_or_else(|| past_end(u64::MAX, mem))?;
+
+    let mut buffer = vec![0u8; page_size];
+
+    read_in_bounds(mem, &mut buffer, offset)?;
+
+    Result::Ok(buffer)
+}
+
+/// Reads `buf.len()` bytes of the image at `offset`, failing with `UnexpectedEof`
+/// if they reach past the end of the allocated memory.
+fn read_in_bounds(mem: &dyn Memory, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
+    let available = mem.capacity().saturating_sub(utils::header_bytes());
+    match offset.checked_add(buf.len() as u64) {
+        Some(end) if end <= available => read(mem, buf, offset),
+        end => Err(past_end(end.unwrap_or(u64::MAX), mem)),
+    }
+}
+
+fn past_end(end: u64, mem: &dyn Memory) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!(
+            "read up to byte {} is past the {} bytes of memory",
+            end,
+            mem.capacity().saturating_sub(utils::header_bytes())
+        ),
+    )
+}
+
+/// Writes a single page directly into the stable-memory VFS.
+///
+/// The page is placed at `(page_number - 1) * page_data.len()`, growing stable
+/// memory and the recorded database size as needed. This bypasses SQLite
+/// entirely, so it is only meant for restore-style tooling. `CONN` is reopened
+/// afterwards so the next query sees the new content, see
+/// [`invalidate_page_cache`]; this takes the `CONN` lock, so don't call it while
+/// holding it. Page numbers start at 1; lower ones fail with
+/// `ErrorKind::InvalidInput`, and a transaction open on `CONN` with
+/// `ErrorKind::WouldBlock`.
+pub fn write_page_to_vfs(page_number: i64, page_data: &[u8]) -> Result<(), io::Error> {
+    let mut conn = CONN.lock().unwrap();
+    check_autocommit(&conn)?;
+    write_page(page_number, page_data)?;
+    reopen(&mut conn).map_err(sqlite_to_io)
+}
+
+/// Reopens `CONN` so SQLite rereads the database from stable memory.
+///
+/// Needed whenever pages were written beneath SQLite, which the restore functions
+/// and [`write_page_to_vfs`] already do on their own. Dropping the page cache
+/// (`PRAGMA shrink_memory`) isn't enough: SQLite keeps the schema it parsed as
+/// long as the schema version in the header is unchanged, so queries could keep
+/// resolving table names to the root pages of the old database. A fresh
+/// connection reads both again.
+///
+/// The new connection gets the same pragmas as `CONN` had when first opened and
+/// the limit set by [`set_max_db_bytes`](crate::set_max_db_bytes). Anything else
+/// set up on the old one is lost: register custom functions and collations again,
+/// and reapply other pragmas such as [`set_secure_delete`](crate::set_secure_delete).
+///
+/// # Errors
+///
+/// Returns an error if the connection can't be reopened, or of kind
+/// `ErrorKind::WouldBlock` if `CONN` has a transaction open, since closing the
+/// connection would roll it back.
+pub fn invalidate_page_cache() -> Result<(), io::Error> {
+    let mut conn = CONN.lock().unwrap();
+    check_autocommit(&conn)?;
+    reopen(&mut conn).map_err(sqlite_to_io)
+}
+
+/// Fails if `conn` has a transaction open; see [`invalidate_page_cache`].
+fn check_autocommit(conn: &Connection) -> Result<(), io::Error> {
+    if conn.is_autocommit() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "a transaction is open on the connection",
+        ))
+    }
+}
+
+/// Replaces `conn` with a fresh connection to the database in stable memory.
+fn reopen(conn: &mut Connection) -> rusqlite::Result<()> {
+    *conn = crate::open_connection()?;
+    refresh_statement_cache(conn)?;
+    Ok(())
+}
+
+fn write_page(page_number: i64, page_data: &[u8]) -> Result<(), io::Error> {
+    if page_number < 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid page number {}", page_number),
+        ));
+    }
+    let offset = (page_number as u64 - 1)
+        .checked_mul(page_data.len() as u64)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "page offset overflows"))?;
+
+    write(&StableMemory, page_data, offset)?;
+    record_page_writes(1);
+
+    Result::Ok(())
+}
+
+/// Returns a token marking the current number of pages written to the VFS.
+///
+/// Pass it to [`pages_written_since`] later on to find out how much changed in
+/// between, e.g. to decide whether an incremental backup is worthwhile.
+pub fn change_token() -> ChangeToken {
+    ChangeToken(PAGES_WRITTEN.load(Ordering::Relaxed))
+}
+
+/// Returns the number of page writes that reached the VFS since `token` was taken.
+///
+/// Every token counts from its own baseline, so taking a new token does not reset
+/// older ones. Rewriting the same page twice counts twice, so this is an upper
+/// bound on the number of distinct dirty pages.
+///
+/// The counter lives on the heap and starts from zero after every upgrade; tokens
+/// taken before an upgrade report `0` until enough new writes happened.
+pub fn pages_written_since(token: ChangeToken) -> u64 {
+    PAGES_WRITTEN
+        .load(Ordering::Relaxed)
+        .saturating_sub(token.0)
+}
+
+pub(crate) fn record_page_writes(pages: u64) {
+    PAGES_WRITTEN.fetch_add(pages, Ordering::Relaxed);
+}
+
+/// Restores `data` only if stable memory doesn't hold a database yet.
+///
+/// Meant for `post_upgrade` (or `init`) to seed a fresh canister from a bundled
+/// backup without clobbering live data on every later upgrade. Stable memory
+/// counts as empty if it was never grown, records a size of zero or doesn't start
+/// with an SQLite header.
+///
+/// # Returns
+///
+/// Returns `true` if the backup was restored, `false` if an existing database was
+/// kept.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as [`db_restore`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::restore_if_empty;
+///
+/// # let seed = Vec::new();
+/// let seeded = restore_if_empty(&seed).expect("Failed to seed the database");
+/// ```
+pub fn restore_if_empty(data: &[u8]) -> Result<bool, BackupError> {
+    if has_database() {
+        return Ok(false);
+    }
+    db_restore(data)?;
+    Ok(true)
+}
+
+fn has_database() -> bool {
+    let _conn = CONN.lock().unwrap();
+    if utils::size(&StableMemory) < SQLITE_HEADER_MAGIC.len() as u64 {
+        return false;
+    }
+    let mut magic = [0u8; SQLITE_HEADER_MAGIC.len()];
+    read(&StableMemory, &mut magic, 0).is_ok() && magic == SQLITE_HEADER_MAGIC
+}
+
+/// Starts a restore whose backup arrives as `total_chunks` chunks of `chunk_size`
+/// bytes, e.g. pulled from another canister one call at a time.
+///
+/// Only the last chunk may be shorter. Chunks are buffered on the heap until the
+/// last one arrives, so the live database is untouched while the transfer is in
+/// progress.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::restore_pull_init;
+///
+/// let pull = restore_pull_init(3, 1 << 20);
+/// assert_eq!(pull.missing_chunks(), vec![0, 1, 2]);
+/// ```
+pub fn restore_pull_init(total_chunks: u32, chunk_size: usize) -> RestorePull {
+    RestorePull {
+        total_chunks,
+        chunk_size,
+        chunks: vec![None; total_chunks as usize],
+        received: 0,
+        restored: false,
+    }
+}
+
+/// Feeds chunk `index` of a restore started with [`restore_pull_init`].
+///
+/// Chunks can arrive in any order, and re-sending a chunk replaces the earlier
+/// copy, so a transfer can be resumed from [`RestorePull::missing_chunks`] after a
+/// failed call. Once the last missing chunk arrives, the backup is restored like
+/// [`db_restore`] in the same message, writing the chunks in order straight from
+/// the buffer. The image is checked and stable memory grown for all of it before
+/// the first page is written, so a failed restore leaves the live database as it
+/// was, and once writing started only a trap can stop it, which rolls back the
+/// whole message. The swap is thus atomic: the live database is either entirely
+/// the old one or entirely the backup. Chunks fed after that are ignored.
+///
+/// # Returns
+///
+/// Returns `true` once the backup is restored, `false` while chunks are missing.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if:
+/// - `index` is out of range or `data` has the wrong length
+///   (`BackupError::InvalidImage`).
+/// - The backup cannot be restored, see [`db_restore`]. The chunks are kept so a
+///   corrupted one can be re-sent.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::cell::RefCell;
+/// use ic_sqlite_features::backup::{restore_pull_feed, restore_pull_init, RestorePull};
+///
+/// thread_local! {
+///     static PULL: RefCell<Option<RestorePull>> = RefCell::new(None);
+/// }
+///
+/// // In the update call receiving chunk `index`:
+/// # let (index, data) = (0, Vec::new());
+/// let done = PULL.with(|pull| {
+///     let mut pull = pull.borrow_mut();
+///     let pull = pull.get_or_insert_with(|| restore_pull_init(16, 1 << 20));
+///     restore_pull_feed(pull, index, data)
+/// })
+/// .expect("Failed to restore");
+/// ```
+pub fn restore_pull_feed(pull: &mut RestorePull, index: u32, data: Vec<u8>) -> Result<bool, BackupError> {
+    if pull.restored {
+        return Ok(true);
+    }
+
+    let total = pull.total_chunks();
+    if index >= total {
+        return Err(BackupError::InvalidImage(format!(
+            "chunk {} is out of range for {} chunks",
+            index, total
+        )));
+    }
+    let is_last = index == total - 1;
+    if data.is_empty() || data.len() > pull.chunk_size || (!is_last && data.len() != pull.chunk_size) {
+        return Err(BackupError::InvalidImage(format!(
+            "chunk {} has {} bytes, expected {}{}",
+            index,
+            data.len(),
+            if is_last { "at most " } else { "" },
+            pull.chunk_size
+        )));
+    }
+
+    let slot = &mut pull.chunks[index as usize];
+    if slot.is_none() {
+        pull.received += 1;
+    }
+    *slot = Some(data);
+    if pull.received < total {
+        return Ok(false);
+    }
+
+    let parts: Vec<&[u8]> = pull.chunks.iter().flatten().map(Vec::as_slice).collect();
+    restore_parts(&parts, &RestoreOptions::default())?;
+    pull.restored = true;
+    pull.chunks = Vec::new();
+    Ok(true)
+}
+
+/// Checks that every table in `expected_tables` exists in the live database.
+///
+/// Call it right after [`db_restore`] (e.g. in `post_upgrade`) to reject a wrong or
+/// outdated backup immediately, instead of failing later with "no such table".
+///
+/// # Errors
+///
+/// Returns `BackupError::MissingTables` listing every missing table, or
+/// `BackupError::Sqlite` if the schema cannot be queried.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{assert_schema, db_restore};
+///
+/// # let backup_data = Vec::new();
+/// db_restore(&backup_data).expect("Failed to restore");
+/// assert_schema(&["person", "orders"]).expect("Restored the wrong database");
+/// ```
+pub fn assert_schema(expected_tables: &[&str]) -> Result<(), BackupError> {
+    let mut missing = Vec::new();
+    for table in expected_tables {
+        if !table_exists(table)? {
+            missing.push(table.to_string());
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(BackupError::MissingTables(missing))
+    }
+}
+
+/// Checks that a backup would be accepted by [`db_restore`] and passes an
+/// integrity check, without restoring it.
+///
+/// The image first goes through the same checks as [`db_restore`]: page size,
+/// length and the [`set_max_db_bytes`](crate::set_max_db_bytes) quota. It is then
+/// loaded into a throwaway read-only in-memory connection, so neither stable
+/// memory nor the live `CONN` are touched. Run this before overwriting live data
+/// with a backup and only proceed if [`RestoreReport::is_clean`] holds.
+///
+/// # Returns
+///
+/// Returns a [`RestoreReport`] with the table count, page count, schema version and
+/// integrity check result of the backup.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if:
+/// - `data` is empty (`BackupError::EmptyDatabase`).
+/// - [`db_restore`] would reject it (`PageSizeMismatch`, `InvalidImage` or
+///   `QuotaExceeded`).
+/// - The bytes cannot be loaded or are not an SQLite database.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{backup::{db_backup_on_memory, dry_run_restore}, CONN};
+///
+/// let backup_data = db_backup_on_memory(&mut CONN.lock().unwrap()).expect("Failed to back up");
+/// let report = dry_run_restore(&backup_data).expect("Failed to open backup");
+/// assert!(report.is_clean());
+/// ```
+pub fn dry_run_restore(data: &[u8]) -> Result<RestoreReport, BackupError> {
+    validate_image(data)?;
+    check_quota(data.len() as u64)?;
+    let conn = open_image_in_memory(data)?;
+
+    let integrity_check = conn
+        .prepare("PRAGMA integrity_check;")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let table_count = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table';",
+        [],
+        |row| row.get(0),
+    )?;
+    let page_count = conn.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    let schema_version = conn.query_row("PRAGMA schema_version;", [], |row| row.get(0))?;
+
+    Ok(RestoreReport {
+        table_count,
+        page_count,
+        schema_version,
+        integrity_check,
+    })
+}
+
+/// Opens a copy of a raw database image as a read-only in-memory connection.
+fn open_image_in_memory(data: &[u8]) -> Result<Connection, BackupError> {
+    if data.is_empty() {
+        return Err(BackupError::EmptyDatabase);
+    }
+
+    let conn = Connection::open_in_memory()?;
+    // SAFETY: the image is copied into a buffer owned by SQLite, which frees it
+    // once the connection is closed (or right away if deserializing fails).
+    let rc = unsafe {
+        let buf = ffi::sqlite3_malloc64(data.len() as u64) as *mut u8;
+        if buf.is_null() {
+            return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_NOMEM), None).into());
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), buf, data.len());
+        ffi::sqlite3_deserialize(
+            conn.handle(),
+            c"main".as_ptr(),
+            buf,
+            data.len() as i64,
+            data.len() as i64,
+            (ffi::SQLITE_DESERIALIZE_FREEONCLOSE | ffi::SQLITE_DESERIALIZE_READONLY) as c_uint,
+        )
+    };
+    if rc != ffi::SQLITE_OK {
+        return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None).into());
+    }
+
+    Ok(conn)
+}
+
+/// Number of rows [`self_test`] writes, enough to span several pages.
+const SELF_TEST_ROWS: i64 = 64;
+
+/// Pages per framed chunk in [`self_test`], so the image spans several chunks.
+const SELF_TEST_CHUNK_PAGES: usize = 4;
+
+/// Name of the VFS [`self_test`] runs its throwaway database on.
+const SELF_TEST_VFS: &str = "self_test";
+
+/// Runs a backup and restore round-trip on a throwaway database.
+///
+/// The round-trip uses the same code as the live database, only over a buffer on
+/// the heap instead of stable memory: test rows are written through the crate's
+/// VFS, the pages are read back like [`db_backup_on_memory`] does, split into
+/// CRC-framed chunks like [`backup_framed_chunk`] produces, verified and
+/// reassembled, then written into the wiped buffer like [`db_restore`] does and
+/// opened through the VFS again. The restored rows must match the originals and
+/// pass an integrity check. Neither stable memory nor `CONN` are touched, so it's
+/// safe to run on canister init or as a CI smoke test.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if any step of the round-trip fails,
+/// with `BackupError::InvalidImage` if the restored data doesn't match.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::self_test;
+///
+/// self_test().expect("Backup round-trip failed");
+/// ```
+pub fn self_test() -> Result<(), BackupError> {
+    let memory = SELF_TEST_MEMORY.lock().unwrap();
+    memory.clear();
+    let open = || -> Result<Connection, BackupError> {
+        let conn = Connection::open_with_flags_and_vfs(
+            "main.db",
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            SELF_TEST_VFS,
+        )?;
+        conn.execute_batch("PRAGMA page_size=4096; PRAGMA journal_mode=MEMORY;")?;
+        Ok(conn)
+    };
+
+    let source = open()?;
+    source.execute_batch(
+        "CREATE TABLE self_test (id INTEGER PRIMARY KEY, name TEXT NOT NULL, data BLOB NOT NULL);",
+    )?;
+    {
+        let mut insert = source.prepare("INSERT INTO self_test (id, name, data) VALUES (?1, ?2, ?3);")?;
+        for id in 0..SELF_TEST_ROWS {
+            insert.execute(params![id, format!("row {}", id), vec![id as u8; 256]])?;
+        }
+    }
+    let expected = self_test_rows(&source)?;
+    let page_count: i64 = source.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    drop(source);
+
+    let mut image = Vec::new();
+    read_image(&**memory, page_count, &mut image)?;
+
+    let mut restored_image = Vec::with_capacity(image.len());
+    for (index, pages) in image.chunks(PAGE_SIZE * SELF_TEST_CHUNK_PAGES).enumerate() {
+        let chunk = frame_pages((index * SELF_TEST_CHUNK_PAGES) as u32 + 1, pages);
+        let (page_start, _) = verify_framed_chunk(&chunk)?;
+        if page_start != (restored_image.len() / PAGE_SIZE) as i64 + 1 {
+            return Err(BackupError::InvalidImage(format!(
+                "self-test chunk starts at page {}",
+                page_start
+            )));
+        }
+        restored_image.extend_from_slice(&chunk[FRAME_HEADER_SIZE..chunk.len() - FRAME_CRC_SIZE]);
+    }
+
+    validate_image(&restored_image)?;
+    memory.clear();
+    let options = RestoreOptions {
+        verify_crc: true,
+        ..RestoreOptions::default()
+    };
+    write_image(&**memory, &[&restored_image], &options)?;
+
+    let restored = open()?;
+    let integrity: String = restored.query_row("PRAGMA integrity_check;", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(BackupError::InvalidImage(format!(
+            "self-test integrity check failed: {}",
+            integrity
+        )));
+    }
+    if self_test_rows(&restored)? != expected {
+        return Err(BackupError::InvalidImage(
+            "self-test rows don't match after restore".to_string(),
+        ));
+    }
+    drop(restored);
+    memory.clear();
+
+    Ok(())
+}
+
+fn self_test_rows(conn: &Connection) -> Result<Vec<(i64, String, Vec<u8>)>, BackupError> {
+    let mut stmt = conn.prepare("SELECT id, name, data FROM self_test ORDER BY id;")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Backs up a range of pages as an independently verifiable framed chunk.
+///
+/// A chunk is laid out as `[u32 page_start][u16 page_count][pages...][u32 crc]`,
+/// all integers big-endian, where `crc` is the CRC-32 of everything before it.
+/// It holds up to `max_pages` pages starting at the 1-based `page_start`; a chunk
+/// with a `page_count` of `0` means `page_start` is past the end of the database.
+/// Downloading a large backup chunk by chunk this way lets the receiver detect
+/// and re-fetch exactly the corrupted chunk with [`verify_framed_chunk`].
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if:
+/// - `page_start` is not a valid page number.
+/// - The page count cannot be queried or page data cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::backup_framed_chunk;
+///
+/// let chunk = backup_framed_chunk(1, 256).expect("Failed to back up chunk");
+/// ```
+pub fn backup_framed_chunk(page_start: i64, max_pages: u16) -> io::Result<Vec<u8>> {
+    let start = u32::try_from(page_start)
+        .ok()
+        .filter(|start| *start > 0)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid page number {}", page_start),
+            )
+        })?;
+
+    let mut conn = CONN.lock().unwrap();
+    let tx = conn.transaction().map_err(sqlite_to_io)?;
+    let page_count: i64 = tx
+        .query_row("PRAGMA page_count;", [], |row| row.get(0))
+        .map_err(sqlite_to_io)?;
+    let pages = (page_count - page_start + 1).clamp(0, max_pages as i64) as u16;
+
+    let mut data = Vec::with_capacity(pages as usize * PAGE_SIZE);
+    for page_number in page_start..page_start + pages as i64 {
+        data.extend_from_slice(&read_page_from_vfs(page_number, PAGE_SIZE)?);
+    }
+    tx.commit().map_err(sqlite_to_io)?;
+
+    Ok(frame_pages(start, &data))
+}
+
+/// Wraps whole pages starting at `page_start` into a framed chunk.
+fn frame_pages(page_start: u32, pages: &[u8]) -> Vec<u8> {
+    let page_count = (pages.len() / PAGE_SIZE) as u16;
+    let mut chunk = Vec::with_capacity(FRAME_HEADER_SIZE + pages.len() + FRAME_CRC_SIZE);
+    chunk.extend_from_slice(&page_start.to_be_bytes());
+    chunk.extend_from_slice(&page_count.to_be_bytes());
+    chunk.extend_from_slice(pages);
+
+    let crc = crc32fast::hash(&chunk);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+/// Verifies a chunk produced by [`backup_framed_chunk`].
+///
+/// # Returns
+///
+/// Returns the `(page_start, page_count)` stored in the chunk header.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if:
+/// - The chunk is truncated, its length doesn't match its page count or its
+///   `page_start` is `0`.
+/// - The stored checksum doesn't match the data.
+///
+/// The CRC only detects corruption in transit: anyone can compute a matching one,
+/// so a chunk that passes isn't proof it came from [`backup_framed_chunk`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{backup_framed_chunk, verify_framed_chunk};
+///
+/// let chunk = backup_framed_chunk(1, 256).expect("Failed to back up chunk");
+/// let (page_start, page_count) = verify_framed_chunk(&chunk).expect("Corrupted chunk");
+/// ```
+pub fn verify_framed_chunk(data: &[u8]) -> Result<(i64, u16), BackupError> {
+    if data.len() < FRAME_HEADER_SIZE + FRAME_CRC_SIZE {
+        return Err(BackupError::InvalidImage(format!(
+            "framed chunk of {} bytes is too short",
+            data.len()
+        )));
+    }
+
+    let page_start = u32::from_be_bytes(data[0..4].try_into().unwrap());
+    let page_count = u16::from_be_bytes(data[4..6].try_into().unwrap());
+    if page_start < 1 {
+        return Err(BackupError::InvalidImage(
+            "framed chunk starts at page 0, pages are numbered from 1".to_string(),
+        ));
+    }
+    let expected_len = FRAME_HEADER_SIZE + page_count as usize * PAGE_SIZE + FRAME_CRC_SIZE;
+    if data.len() != expected_len {
+        return Err(BackupError::InvalidImage(format!(
+            "framed chunk of {} pages should be {} bytes, got {}",
+            page_count,
+            expected_len,
+            data.len()
+        )));
+    }
+
+    let (body, crc) = data.split_at(data.len() - FRAME_CRC_SIZE);
+    let expected = u32::from_be_bytes(crc.try_into().unwrap());
+    let actual = crc32fast::hash(body);
+    if expected != actual {
+        return Err(BackupError::ChecksumMismatch { expected, actual });
+    }
+
+    Ok((page_start as i64, page_count))
+}
+
+/// Verifies a framed chunk and writes its pages back at their original offsets.
+///
+/// Feed every chunk of a framed backup, in any order, to rebuild the database in
+/// stable memory. The chunk is verified in full before anything is written, and
+/// checked like [`db_restore`] checks an image: the chunk holding page 1 must
+/// start with an SQLite header using the VFS's page size, and no chunk may reach
+/// past the limit set by [`set_max_db_bytes`](crate::set_max_db_bytes). Like
+/// [`db_restore`], this reopens `CONN` after writing, so functions and collations
+/// registered on it have to be registered again.
+///
+/// # Returns
+///
+/// Returns the `(page_start, page_count)` of the restored chunk.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if:
+/// - The chunk fails verification, see [`verify_framed_chunk`].
+/// - It holds page 1 and that page isn't a valid SQLite header page
+///   (`BackupError::InvalidImage` or `BackupError::PageSizeMismatch`).
+/// - Its last page ends past the size limit (`BackupError::QuotaExceeded`).
+/// - `CONN` has a transaction open (`ErrorKind::WouldBlock`).
+/// - Its pages cannot be written.
+pub fn restore_framed_chunk(data: &[u8]) -> Result<(i64, u16), BackupError> {
+    let (page_start, page_count) = verify_framed_chunk(data)?;
+
+    let pages = &data[FRAME_HEADER_SIZE..data.len() - FRAME_CRC_SIZE];
+    if page_start == 1 && page_count > 0 {
+        validate_image(pages)?;
+    }
+    check_quota((page_start as u64 - 1 + page_count as u64) * PAGE_SIZE as u64)?;
+
+    let mut conn = CONN.lock().unwrap();
+    check_autocommit(&conn)?;
+    for (index, page_data) in pages.chunks_exact(PAGE_SIZE).enumerate() {
+        write_page(page_start + index as i64, page_data)?;
+    }
+    reopen(&mut conn)?;
+
+    Ok((page_start, page_count))
+}
+
+/// Opens a [`BackupSession`] serving the database in chunks of `chunk_size` bytes.
+///
+/// The database size is fixed when the session is opened and the session is kept
+/// in canister memory, so every later message can fetch its chunk by session id.
+/// Sessions don't survive upgrades; close them with [`close_backup_session`] once
+/// the download is done.
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if `chunk_size` is `0`, the page count
+/// cannot be queried or the database has no pages yet, with a
+/// [`BackupError::EmptyDatabase`] inside.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{backup_session, open_backup_session};
+///
+/// // In one message:
+/// let session = open_backup_session(1024 * 1024).expect("Failed to open session");
+/// let (id, total) = (session.id(), session.total_chunks());
+///
+/// // In each following message:
+/// let chunk = backup_session(id).unwrap().chunk(0).expect("Failed to read chunk");
+/// ```
+pub fn open_backup_session(chunk_size: usize) -> io::Result<BackupSession> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must be greater than 0",
+        ));
+    }
+
+    let conn = CONN.lock().unwrap();
+    let page_count: i64 = conn
+        .query_row("PRAGMA page_count;", [], |row| row.get(0))
+        .map_err(sqlite_to_io)?;
+    if page_count == 0 {
+        return Err(io::Error::other(BackupError::EmptyDatabase));
+    }
+
+    let session = BackupSession {
+        id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        chunk_size,
+        total_bytes: page_count as u64 * PAGE_SIZE as u64,
+        token: change_token(),
+    };
+    BACKUP_SESSIONS
+        .lock()
+        .unwrap()
+        .insert(session.id, session.clone());
+
+    Ok(session)
+}
+
+/// Returns the open [`BackupSession`] with the given id.
+pub fn backup_session(id: u64) -> Option<BackupSession> {
+    BACKUP_SESSIONS.lock().unwrap().get(&id).cloned()
+}
+
+/// Closes a [`BackupSession`], returning whether it was open.
+pub fn close_backup_session(id: u64) -> bool {
+    BACKUP_SESSIONS.lock().unwrap().remove(&id).is_some()
+}
+
+impl BackupSession {
+    /// Returns the id to look the session up with in later messages.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the size of the backup in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Returns the number of chunks making up the backup.
+    pub fn total_chunks(&self) -> u32 {
+        self.total_bytes.div_ceil(self.chunk_size as u64) as u32
+    }
+
+    /// Reads chunk number `index` of the backup.
+    ///
+    /// The same index always yields the same bytes. If the database was written to
+    /// since the session was opened, the chunks would no longer form a consistent
+    /// image, so an error is returned instead and the download has to start over
+    /// with a new session.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an `io::Error` if:
+    /// - `index` is not below [`total_chunks`](Self::total_chunks).
+    /// - The database changed since the session was opened.
+    /// - The chunk reaches past the end of the allocated stable memory
+    ///   (`ErrorKind::UnexpectedEof`).
+    pub fn chunk(&self, index: u32) -> io::Result<Vec<u8>> {
+        if index >= self.total_chunks() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunk {} out of range, session has {}", index, self.total_chunks()),
+            ));
+        }
+
+        let _conn = CONN.lock().unwrap();
+        if pages_written_since(self.token) > 0 {
+            return Err(io::Error::other(format!(
+                "database changed since backup session {} was opened",
+                self.id
+            )));
+        }
+
+        let offset = index as u64 * self.chunk_size as u64;
+        let len = (self.total_bytes - offset).min(self.chunk_size as u64);
+        let mut buffer = vec![0u8; len as usize];
+        read_in_bounds(&StableMemory, &mut buffer, offset)?;
+        Ok(buffer)
+    }
+}
+
+impl BackupState {
+    /// Creates the state for a new backup.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Copies up to `max_pages_per_step` more pages of a backup into `state`.
+///
+/// A single backup of a large database may exceed the instruction limit of one
+/// message. Calling this once per message or timer tick spreads the work instead,
+/// until it returns [`BackupStepResult::Done`] with the whole image. The page count
+/// is fixed by the first step, and `state` is reset once done so it can be reused
+/// for the next backup.
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if:
+/// - `max_pages_per_step` is not positive.
+/// - The database is empty, see [`BackupError::EmptyDatabase`].
+/// - The database was written to since the first step, which would make the
+///   pages copied so far inconsistent. `state` is reset, so the next call starts
+///   over.
+/// - The page count cannot be queried or page data cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{backup_incremental_step, BackupState, BackupStepResult};
+///
+/// let mut state = BackupState::new();
+/// loop {
+///     // In a canister, each iteration would run in its own timer callback.
+///     match backup_incremental_step(&mut state, 1024).expect("Backup step failed") {
+///         BackupStepResult::InProgress { .. } => continue,
+///         BackupStepResult::Done(backup_data) => break,
+///     }
+/// }
+/// ```
+pub fn backup_incremental_step(
+    state: &mut BackupState,
+    max_pages_per_step: i64,
+) -> io::Result<BackupStepResult> {
+    if max_pages_per_step <= 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "max_pages_per_step must be positive",
+        ));
+    }
+
+    let conn = CONN.lock().unwrap();
+    let token = match state.token {
+        Some(token) => token,
+        None => {
+            let total: i64 = conn
+                .query_row("PRAGMA page_count;", [], |row| row.get(0))
+                .map_err(sqlite_to_io)?;
+            if total == 0 {
+                return Err(io::Error::other(BackupError::EmptyDatabase));
+            }
+            state.total = total;
+            *state.token.insert(change_token())
+        }
+    };
+    if pages_written_since(token) > 0 {
+        *state = BackupState::new();
+        return Err(io::Error::other(
+            "database changed during the incremental backup",
+        ));
+    }
+
+    let end = (state.pages_done + max_pages_per_step).min(state.total);
+    for page_number in state.pages_done + 1..=end {
+        state
+            .buffer
+            .extend_from_slice(&read_page_from_vfs(page_number, PAGE_SIZE)?);
+    }
+    state.pages_done = end;
+
+    if state.pages_done < state.total {
+        return Ok(BackupStepResult::InProgress {
+            pages_done: state.pages_done,
+            total: state.total,
+        });
+    }
+    Ok(BackupStepResult::Done(std::mem::take(state).buffer))
+}
+
+/// Lazily yields every page of the database as `(page_number, page_bytes)`.
+///
+/// This is the raw building block for custom backup formats (framed, encrypted,
+/// deduplicated, ...): pages are read one at a time, in order, starting at page 1.
+///
+/// The iterator holds the `CONN` lock for its whole lifetime, which keeps the
+/// pages consistent but blocks every other use of the database, including the
+/// other functions of this crate. Drop it as soon as you are done.
+///
+/// # Errors
+///
+/// Returns an `io::Error` if the page count cannot be queried; each item is an
+/// error if its page cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::pages;
+///
+/// for page in pages().expect("Failed to list pages") {
+///     let (page_number, page_data) = page.expect("Failed to read page");
+///     // ...
+/// }
+/// ```
+pub fn pages() -> io::Result<impl Iterator<Item = io::Result<(i64, Vec<u8>)>>> {
+    let conn = CONN.lock().unwrap();
+    let page_count: i64 = conn
+        .query_row("PRAGMA page_count;", [], |row| row.get(0))
+        .map_err(sqlite_to_io)?;
+
+    Ok(Pages {
+        _conn: conn,
+        next: 1,
+        page_count,
+    })
+}
+
+impl Manifest {
+    /// Encodes the manifest into its [`MANIFEST_SIZE`]-byte form.
+    pub fn to_bytes(&self) -> [u8; MANIFEST_SIZE] {
+        let mut bytes = [0u8; MANIFEST_SIZE];
+        bytes[0..8].copy_from_slice(MANIFEST_MAGIC);
+        bytes[8..10].copy_from_slice(&MANIFEST_VERSION.to_be_bytes());
+        bytes[10..14].copy_from_slice(&self.page_size.to_be_bytes());
+        bytes[14..22].copy_from_slice(&self.page_count.to_be_bytes());
+        bytes[22..26].copy_from_slice(&self.schema_version.to_be_bytes());
+        bytes[26..34].copy_from_slice(&self.timestamp_ns.to_be_bytes());
+        bytes[34..66].copy_from_slice(&self.image_hash);
+        let crc = crc32fast::hash(&bytes[..66]);
+        bytes[66..70].copy_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+}
+
+/// Performs a backup like [`db_backup_on_memory`], prefixed with a [`Manifest`].
+///
+/// The first [`MANIFEST_SIZE`] bytes hold the manifest and the raw database image
+/// follows; restore it with `db_restore(&backup[MANIFEST_SIZE..])`. Keep the
+/// manifest in front when compressing or otherwise encoding the image, so
+/// [`peek_backup_info`] keeps working on the first bytes alone. Check the whole
+/// backup with [`verify_backup_with_manifest`] before restoring it.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as
+/// [`db_backup_on_memory`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{backup::{db_backup_with_manifest, peek_backup_info}, CONN};
+///
+/// let backup = db_backup_with_manifest(&mut CONN.lock().unwrap()).expect("Failed to back up");
+/// let manifest = peek_backup_info(&backup).expect("Invalid manifest");
+/// ```
+pub fn db_backup_with_manifest(conn: &mut Connection) -> Result<Vec<u8>, BackupError> {
+    let schema_version: i32 = conn.query_row("PRAGMA schema_version;", [], |row| row.get(0))?;
+    let image = db_backup_on_memory(conn)?;
+
+    let manifest = Manifest {
+        page_size: PAGE_SIZE as u32,
+        page_count: (image.len() / PAGE_SIZE) as u64,
+        schema_version,
+        timestamp_ns: time(),
+        image_hash: backup_content_hash(&image),
+    };
+
+    let mut backup = Vec::with_capacity(MANIFEST_SIZE + image.len());
+    backup.extend_from_slice(&manifest.to_bytes());
+    backup.extend_from_slice(&image);
+    Ok(backup)
+}
+
+/// Runs the usual cleanup and then takes a backup, in a single call.
+///
+/// The steps enabled in `opts` run in this order: checkpoint, optimize, vacuum,
+/// backup, verify. `CONN` stays locked from the first step until the backup is
+/// taken, so no write can slip in between. The backup is uncompressed. Every
+/// finished step is recorded in the [`maintenance_log`].
+///
+/// # Returns
+///
+/// Returns the backup, prefixed with a [`Manifest`] if `opts.with_manifest` is set.
+/// The manifest holds a hash of the image, so the backup can be checked with
+/// [`verify_backup_with_manifest`] wherever it ends up.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if any step fails, or
+/// `BackupError::InvalidImage` if the backup doesn't pass verification.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{compact_and_backup, MaintenanceOptions};
+///
+/// let opts = MaintenanceOptions { vacuum: true, ..MaintenanceOptions::default() };
+/// let backup = compact_and_backup(opts).expect("Maintenance failed");
+/// ```
+pub fn compact_and_backup(opts: MaintenanceOptions) -> Result<Vec<u8>, BackupError> {
+    let backup = {
+        let mut conn = CONN.lock().unwrap();
+        if opts.checkpoint {
+            // Outside of WAL mode the checkpointed frame count is -1.
+            let frames: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| row.get(2))?;
+            log_maintenance(MaintenanceAction::Checkpoint { frames: frames.max(0) });
+        }
+        if opts.optimize {
+            conn.execute_batch("PRAGMA optimize;")?;
+            log_maintenance(MaintenanceAction::Optimize);
+        }
+        if opts.vacuum {
+            let before: i64 = conn.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+            conn.execute_batch("VACUUM;")?;
+            let after: i64 = conn.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+            let pages_reclaimed = before - after;
+            log_maintenance(MaintenanceAction::Vacuum { pages_reclaimed });
+        }
+        if opts.with_manifest {
+            db_backup_with_manifest(&mut conn)?
+        } else {
+            db_backup_on_memory(&mut conn)?
+        }
+    };
+    log_maintenance(MaintenanceAction::Backup {
+        bytes: backup.len() as u64,
+    });
+
+    if opts.verify {
+        let image = if opts.with_manifest {
+            verify_backup_with_manifest(&backup)?;
+            &backup[MANIFEST_SIZE..]
+        } else {
+            &backup[..]
+        };
+        let report = dry_run_restore(image)?;
+        if !report.is_clean() {
+            return Err(BackupError::InvalidImage(format!(
+                "backup failed its integrity check: {}",
+                report.integrity_check.join("; ")
+            )));
+        }
+    }
+
+    Ok(backup)
+}
+
+/// Returns the most recent maintenance events, oldest first.
+///
+/// The log holds the last 32 steps run by [`compact_and_backup`]. It lives on the
+/// heap, so it starts out empty after every upgrade.
+pub fn maintenance_log() -> Vec<MaintenanceEvent> {
+    MAINTENANCE_LOG.lock().unwrap().iter().copied().collect()
+}
+
+/// Returns how many pages the most recent logged `VACUUM` reclaimed, if any.
+pub fn last_vacuum_savings() -> Option<i64> {
+    MAINTENANCE_LOG.lock().unwrap().iter().rev().find_map(|event| match event.action {
+        MaintenanceAction::Vacuum { pages_reclaimed } => Some(pages_reclaimed),
+        _ => None,
+    })
+}
+
+fn log_maintenance(action: MaintenanceAction) {
+    let mut log = MAINTENANCE_LOG.lock().unwrap();
+    if log.len() == MAINTENANCE_LOG_CAPACITY {
+        log.pop_front();
+    }
+    log.push_back(MaintenanceEvent {
+        timestamp_ns: time(),
+        action,
+    });
+}
+
+/// Reads the [`Manifest`] from the first bytes of a backup.
+///
+/// Only the first [`MANIFEST_SIZE`] bytes of `header` are looked at, so it's enough
+/// to fetch just those from wherever the backup is stored.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` if `header` is shorter than
+/// [`MANIFEST_SIZE`], doesn't start with a manifest, uses an unknown manifest
+/// version or fails its checksum.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::backup::{peek_backup_info, Manifest};
+///
+/// let manifest = Manifest {
+///     page_size: 4096,
+///     page_count: 3,
+///     schema_version: 1,
+///     timestamp_ns: 0,
+///     image_hash: [0; 32],
+/// };
+/// assert_eq!(peek_backup_info(&manifest.to_bytes()).unwrap(), manifest);
+/// ```
+pub fn peek_backup_info(header: &[u8]) -> Result<Manifest, BackupError> {
+    if header.len() < MANIFEST_SIZE {
+        return Err(BackupError::InvalidImage(format!(
+            "manifest needs {} bytes, got {}",
+            MANIFEST_SIZE,
+            header.len()
+        )));
+    }
+    if &header[0..8] != MANIFEST_MAGIC {
+        return Err(BackupError::InvalidImage(
+            "backup doesn't start with a manifest".to_string(),
+        ));
+    }
+    let version = u16::from_be_bytes(header[8..10].try_into().unwrap());
+    if version != MANIFEST_VERSION {
+        return Err(BackupError::InvalidImage(format!(
+            "unsupported manifest version {}",
+            version
+        )));
+    }
+    let expected = u32::from_be_bytes(header[66..70].try_into().unwrap());
+    let actual = crc32fast::hash(&header[..66]);
+    if expected != actual {
+        return Err(BackupError::ChecksumMismatch { expected, actual });
+    }
+
+    Ok(Manifest {
+        page_size: u32::from_be_bytes(header[10..14].try_into().unwrap()),
+        page_count: u64::from_be_bytes(header[14..22].try_into().unwrap()),
+        schema_version: i32::from_be_bytes(header[22..26].try_into().unwrap()),
+        timestamp_ns: u64::from_be_bytes(header[26..34].try_into().unwrap()),
+        image_hash: header[34..66].try_into().unwrap(),
+    })
+}
+
+/// Checks a backup made by [`db_backup_with_manifest`] against its manifest.
+///
+/// On top of [`peek_backup_info`], the image behind the manifest must hold
+/// `page_count` pages of `page_size` bytes and hash to `image_hash`, so a
+/// corrupted or truncated image is caught by anyone holding the backup alone.
+///
+/// # Returns
+///
+/// Returns the manifest; restore the image with
+/// `db_restore(&backup[MANIFEST_SIZE..])`.
+///
+/// # Errors
+///
+/// This function returns a `BackupError` for the same reasons as
+/// [`peek_backup_info`], or `BackupError::InvalidImage` if the image doesn't
+/// match the manifest.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::backup::{db_restore, verify_backup_with_manifest, MANIFEST_SIZE};
+///
+/// # let backup: Vec<u8> = Vec::new();
+/// verify_backup_with_manifest(&backup).expect("Corrupted backup");
+/// db_restore(&backup[MANIFEST_SIZE..]).expect("Failed to restore");
+/// ```
+pub fn verify_backup_with_manifest(backup: &[u8]) -> Result<Manifest, BackupError> {
+    let manifest = peek_backup_info(backup)?;
+    let image = &backup[MANIFEST_SIZE..];
+
+    let expected_len = (manifest.page_count as u128) * (manifest.page_size as u128);
+    if image.len() as u128 != expected_len {
+        return Err(BackupError::InvalidImage(format!(
+            "manifest lists {} pages of {} bytes, but the image has {} bytes",
+            manifest.page_count,
+            manifest.page_size,
+            image.len()
+        )));
+    }
+    if backup_content_hash(image) != manifest.image_hash {
+        return Err(BackupError::InvalidImage(
+            "image doesn't match the hash in its manifest".to_string(),
+        ));
+    }
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_page_stops_at_the_end_of_memory() {
+        // One WebAssembly page minus the 8-byte size record: not a whole number
+        // of database pages, so the 16th page is cut short.
+        let memory = HeapMemory::default();
+        memory.grow(1).unwrap();
+        memory.write(utils::header_bytes() + 14 * PAGE_SIZE as u64, &[7; PAGE_SIZE]);
+
+        assert_eq!(read_page(&memory, 15, PAGE_SIZE).unwrap(), vec![7; PAGE_SIZE]);
+        let err = read_page(&memory, 16, PAGE_SIZE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = read_page(&memory, i64::MAX, PAGE_SIZE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_page_rejects_page_numbers_below_one() {
+        let memory = HeapMemory::default();
+        memory.grow(1).unwrap();
+
+        for page_number in [0, -1, i64::MIN] {
+            let err = read_page(&memory, page_number, PAGE_SIZE).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn written_pages_are_seen_by_the_next_query() {
+        let _lock = crate::test_lock();
+        let read_value = || -> String {
+            CONN.lock()
+                .unwrap()
+                .query_row("SELECT value FROM page_write;", [], |row| row.get(0))
+                .unwrap()
+        };
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS page_write;
+                 CREATE TABLE page_write (value TEXT NOT NULL);
+                 INSERT INTO page_write VALUES ('value-0');",
+            )
+            .unwrap();
+        assert_eq!(read_value(), "value-0");
+
+        let page_count: i64 = CONN
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA page_count;", [], |row| row.get(0))
+            .unwrap();
+        let (page_number, mut page, at) = (1..=page_count)
+            .find_map(|page_number| {
+                let page = read_page_from_vfs(page_number, PAGE_SIZE).unwrap();
+                let at = page.windows(7).position(|bytes| bytes == b"value-0")?;
+                Some((page_number, page, at))
+            })
+            .unwrap();
+
+        // Written beneath SQLite, the page stays stale in its cache...
+        page[at..at + 7].copy_from_slice(b"value-1");
+        write_page(page_number, &page).unwrap();
+        assert_eq!(read_value(), "value-0");
+        // ...until the connection is reopened.
+        invalidate_page_cache().unwrap();
+        assert_eq!(read_value(), "value-1");
+
+        page[at..at + 7].copy_from_slice(b"value-2");
+        write_page_to_vfs(page_number, &page).unwrap();
+        assert_eq!(read_value(), "value-2");
+    }
+
+    #[test]
+    fn restore_replaces_the_schema_of_the_same_version() {
+        let _lock = crate::test_lock();
+        let query = |sql: &str| -> rusqlite::Result<String> {
+            CONN.lock().unwrap().query_row(sql, [], |row| row.get(0))
+        };
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS live_a;
+                 DROP TABLE IF EXISTS other_b;
+                 CREATE TABLE other_b (y TEXT NOT NULL);
+                 INSERT INTO other_b VALUES ('restored');",
+            )
+            .unwrap();
+        let mut backup = db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE other_b;
+                 CREATE TABLE live_a (x TEXT NOT NULL);
+                 INSERT INTO live_a VALUES ('live');",
+            )
+            .unwrap();
+        assert_eq!(query("SELECT x FROM live_a;").unwrap(), "live");
+
+        // Give the image the live schema version, so SQLite can't tell from the
+        // header that the schema changed.
+        let schema_version: i64 = CONN
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA schema_version;", [], |row| row.get(0))
+            .unwrap();
+        backup[40..44].copy_from_slice(&(schema_version as u32).to_be_bytes());
+        db_restore(&backup).unwrap();
+
+        assert_eq!(query("SELECT y FROM other_b;").unwrap(), "restored");
+        assert!(query("SELECT x FROM live_a;").is_err());
+        CONN.lock().unwrap().execute_batch("DROP TABLE other_b;").unwrap();
+    }
+
+    #[test]
+    fn restores_fail_while_a_transaction_is_open() {
+        let _lock = crate::test_lock();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS open_txn;
+                 CREATE TABLE open_txn (x);",
+            )
+            .unwrap();
+        let backup = db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+
+        CONN.lock().unwrap().execute_batch("BEGIN;").unwrap();
+        let result = db_restore(&backup);
+        CONN.lock().unwrap().execute_batch("ROLLBACK;").unwrap();
+        assert!(
+            matches!(&result, Err(BackupError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn restore_pull_feed_restores_chunks_in_order() {
+        let _lock = crate::test_lock();
+        let count = || -> i64 {
+            CONN.lock()
+                .unwrap()
+                .query_row("SELECT count(*) FROM pull;", [], |row| row.get(0))
+                .unwrap()
+        };
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS pull;
+                 CREATE TABLE pull (data BLOB NOT NULL);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 40)
+                 INSERT INTO pull SELECT zeroblob(1000) FROM n;",
+            )
+            .unwrap();
+        let backup = db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+        CONN.lock().unwrap().execute_batch("DELETE FROM pull;").unwrap();
+        assert_eq!(count(), 0);
+
+        // Chunks that don't line up with pages, fed out of order.
+        let chunk_size = 5000;
+        let chunks: Vec<Vec<u8>> = backup.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+        let mut pull = restore_pull_init(chunks.len() as u32, chunk_size);
+        for (index, chunk) in chunks.iter().enumerate().rev() {
+            let done = restore_pull_feed(&mut pull, index as u32, chunk.clone()).unwrap();
+            assert_eq!(done, index == 0);
+        }
+        assert_eq!(count(), 40);
+        assert_eq!(db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap(), backup);
+    }
+
+    #[test]
+    fn validate_parts_reads_the_header_across_parts() {
+        let mut image = vec![0u8; 2 * PAGE_SIZE];
+        image[..16].copy_from_slice(SQLITE_HEADER_MAGIC);
+        image[16..18].copy_from_slice(&8192u16.to_be_bytes());
+        let parts: Vec<&[u8]> = image.chunks(5).collect();
+
+        assert!(matches!(
+            validate_parts(&parts),
+            Err(BackupError::PageSizeMismatch { backup: 8192, expected: 4096 })
+        ));
+        image[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        assert!(validate_parts(&image.chunks(5).collect::<Vec<_>>()).is_ok());
+        assert!(matches!(
+            validate_parts(&image[..PAGE_SIZE + 1].chunks(5).collect::<Vec<_>>()),
+            Err(BackupError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn empty_databases_are_not_backed_up() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert!(matches!(db_backup_on_memory(&mut conn), Err(BackupError::EmptyDatabase)));
+        let err = stream_db_backup(&mut conn).err().unwrap();
+        assert!(matches!(
+            err.get_ref().and_then(|err| err.downcast_ref::<BackupError>()),
+            Some(BackupError::EmptyDatabase)
+        ));
+    }
+
+    #[test]
+    fn framed_chunks_must_start_at_page_one_or_later() {
+        let chunk = frame_pages(0, &[0; PAGE_SIZE]);
+
+        assert!(matches!(verify_framed_chunk(&chunk), Err(BackupError::InvalidImage(_))));
+        assert!(matches!(restore_framed_chunk(&chunk), Err(BackupError::InvalidImage(_))));
+    }
+
+    #[test]
+    fn restore_framed_chunk_checks_the_header_page() {
+        let mut page = [0u8; PAGE_SIZE];
+        assert!(matches!(
+            restore_framed_chunk(&frame_pages(1, &page)),
+            Err(BackupError::InvalidImage(_))
+        ));
+
+        page[..16].copy_from_slice(SQLITE_HEADER_MAGIC);
+        page[16..18].copy_from_slice(&1024u16.to_be_bytes());
+        assert!(matches!(
+            restore_framed_chunk(&frame_pages(1, &page)),
+            Err(BackupError::PageSizeMismatch { backup: 1024, expected: 4096 })
+        ));
+    }
+
+    #[test]
+    fn dry_run_restore_rejects_what_db_restore_rejects() {
+        let _lock = crate::test_lock();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS dry_run;
+                 CREATE TABLE dry_run (data BLOB NOT NULL);
+                 INSERT INTO dry_run VALUES (zeroblob(10000));",
+            )
+            .unwrap();
+        let mut backup = db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+        assert!(dry_run_restore(&backup).unwrap().is_clean());
+
+        crate::set_max_db_bytes(PAGE_SIZE as u64);
+        let result = dry_run_restore(&backup);
+        crate::set_max_db_bytes(0);
+        assert!(matches!(result, Err(BackupError::QuotaExceeded { .. })), "{:?}", result);
+
+        backup.push(0);
+        assert!(matches!(dry_run_restore(&backup), Err(BackupError::InvalidImage(_))));
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA page_size = 8192; CREATE TABLE t (x);").unwrap();
+        // SAFETY: the serialized copy is owned by us and freed right after copying.
+        let image = unsafe {
+            let mut len = 0;
+            let data = ffi::sqlite3_serialize(conn.handle(), c"main".as_ptr(), &mut len, 0);
+            let image = std::slice::from_raw_parts(data, len as usize).to_vec();
+            ffi::sqlite3_free(data as *mut _);
+            image
+        };
+        assert!(matches!(
+            dry_run_restore(&image),
+            Err(BackupError::PageSizeMismatch { backup: 8192, expected: 4096 })
+        ));
+    }
+
+    #[test]
+    fn verify_backup_with_manifest_checks_the_image() {
+        let _lock = crate::test_lock();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS manifest;
+                 CREATE TABLE manifest (x);",
+            )
+            .unwrap();
+        let image = db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+        let manifest = Manifest {
+            page_size: PAGE_SIZE as u32,
+            page_count: (image.len() / PAGE_SIZE) as u64,
+            schema_version: 1,
+            timestamp_ns: 0,
+            image_hash: backup_content_hash(&image),
+        };
+        let mut backup = manifest.to_bytes().to_vec();
+        backup.extend_from_slice(&image);
+        assert_eq!(verify_backup_with_manifest(&backup).unwrap(), manifest);
+
+        let last = backup.len() - 1;
+        backup[last] ^= 1;
+        assert!(matches!(verify_backup_with_manifest(&backup), Err(BackupError::InvalidImage(_))));
+        backup.truncate(last + 1 - PAGE_SIZE);
+        assert!(matches!(verify_backup_with_manifest(&backup), Err(BackupError::InvalidImage(_))));
+        CONN.lock().unwrap().execute_batch("DROP TABLE manifest;").unwrap();
+    }
+
+    /// Counts the calls made to the memory it wraps, one per system call on the IC.
+    #[derive(Default)]
+    struct CountingMemory {
+        inner: HeapMemory,
+        calls: AtomicU64,
+        bytes_written: AtomicU64,
+    }
+
+    impl Memory for CountingMemory {
+        fn pages(&self) -> u64 {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.inner.pages()
+        }
+
+        fn grow(&self, pages: u64) -> Result<u64, ic_cdk::api::stable::StableMemoryError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.inner.grow(pages)
+        }
+
+        fn read(&self, offset: u64, buf: &mut [u8]) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.inner.read(offset, buf)
+        }
+
+        fn write(&self, offset: u64, buf: &[u8]) {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.bytes_written.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            self.inner.write(offset, buf)
+        }
+    }
+
+    #[test]
+    fn batched_restores_make_fewer_memory_calls() {
+        let image = vec![0u8; 10_000 * PAGE_SIZE];
+        let calls = |write_batch_pages| {
+            let memory = CountingMemory::default();
+            let options = RestoreOptions { write_batch_pages, verify_crc: false };
+            write_image(&memory, &[&image], &options).unwrap();
+            // Every page is copied once, plus an 8-byte size record per write.
+            let writes = image.len().div_ceil(write_batch_pages * PAGE_SIZE) as u64 + 1;
+            assert_eq!(memory.bytes_written.load(Ordering::Relaxed), image.len() as u64 + 8 * writes);
+            memory.calls.load(Ordering::Relaxed)
+        };
+
+        let (one, sixty_four, two_fifty_six) = (calls(1), calls(64), calls(256));
+        assert!(sixty_four * 60 < one, "{} vs {}", sixty_four, one);
+        assert!(two_fifty_six < sixty_four, "{} vs {}", two_fifty_six, sixty_four);
+    }
+
+    #[test]
+    fn read_in_bounds_rejects_overflowing_ranges() {
+        let memory = HeapMemory::default();
+        memory.grow(1).unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = read_in_bounds(&memory, &mut buf, u64::MAX - 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,71 @@
+//! BLOB helpers built on SQLite's incremental BLOB I/O.
+//!
+//! Going through `blob_open` copies the value straight between the row and the
+//! caller's buffer instead of binding it as a parameter, which keeps peak memory
+//! down for large values on a canister.
+
+use rusqlite::{params, DatabaseName, Error};
+
+use crate::query::{lock_conn, quote_identifier};
+use crate::CONN;
+
+/// Reads the BLOB stored in `table.column` of the row with the given `rowid`.
+///
+/// # Errors
+///
+/// Returns an error if an identifier is invalid, the row doesn't exist or the
+/// value cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::blob::get_blob;
+///
+/// let thumbnail = get_blob("images", "thumbnail", 1).expect("Failed to read blob");
+/// ```
+pub fn get_blob(table: &str, column: &str, rowid: i64) -> rusqlite::Result<Vec<u8>> {
+    quote_identifier(table)?;
+    quote_identifier(column)?;
+
+    let conn = CONN.lock().unwrap();
+    let blob = conn.blob_open(DatabaseName::Main, table, column, rowid, true)?;
+    let mut data = vec![0u8; blob.len()];
+    blob.read_at_exact(&mut data, 0)?;
+    Ok(data)
+}
+
+/// Stores `data` into `table.column` of the existing row with the given `rowid`.
+///
+/// The column is first resized with `zeroblob` and then filled through an
+/// incremental BLOB handle, so `data` is never bound as a parameter.
+///
+/// # Errors
+///
+/// Returns `QueryReturnedNoRows` if no row has the given `rowid`, or an error if an
+/// identifier is invalid or the value cannot be written.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::blob::put_blob;
+///
+/// put_blob("images", "thumbnail", 1, &[0x89, 0x50, 0x4e, 0x47]).expect("Failed to write blob");
+/// ```
+pub fn put_blob(table: &str, column: &str, rowid: i64, data: &[u8]) -> rusqlite::Result<()> {
+    let sql = format!(
+        "UPDATE {} SET {} = zeroblob(?1) WHERE rowid = ?2",
+        quote_identifier(table)?,
+        quote_identifier(column)?
+    );
+
+    let mut conn = lock_conn()?;
+    let tx = conn.transaction()?;
+    if tx.execute(&sql, params![data.len() as i64, rowid])? == 0 {
+        return Err(Error::QueryReturnedNoRows);
+    }
+    {
+        let mut blob = tx.blob_open(DatabaseName::Main, table, column, rowid, false)?;
+        blob.write_all_at(data, 0)?;
+    }
+    tx.commit()
+}
@@ -0,0 +1,63 @@
+//! Candid-typed query results, for returning ad-hoc queries from a canister.
+//!
+//! [`query_candid`] is the Candid counterpart of
+//! [`query_values`](crate::query::query_values): every cell becomes a [`Cell`],
+//! so a frontend can render any result set without knowing its types upfront.
+
+use candid::CandidType;
+use rusqlite::types::ValueRef;
+use rusqlite::Params;
+
+use crate::query::lock_conn;
+
+/// A single value of a query result, matching SQLite's storage classes.
+#[derive(CandidType, Debug, Clone, PartialEq)]
+pub enum Cell {
+    Null,
+    Int(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl From<ValueRef<'_>> for Cell {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => Cell::Null,
+            ValueRef::Integer(i) => Cell::Int(i),
+            ValueRef::Real(f) => Cell::Real(f),
+            ValueRef::Text(text) => Cell::Text(String::from_utf8_lossy(text).into_owned()),
+            ValueRef::Blob(blob) => Cell::Blob(blob.to_vec()),
+        }
+    }
+}
+
+/// Runs a query and returns its column names together with every row as [`Cell`]s.
+///
+/// Text that isn't valid UTF-8 is converted lossily, since Candid text must be
+/// UTF-8.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{cell::{query_candid, Cell}, params};
+///
+/// // Exported as a `#[query]` method of the canister.
+/// fn adults() -> (Vec<String>, Vec<Vec<Cell>>) {
+///     query_candid("SELECT id, name FROM person WHERE age > ?1", params![18]).unwrap()
+/// }
+/// ```
+pub fn query_candid(sql: &str, params: impl Params) -> rusqlite::Result<(Vec<String>, Vec<Vec<Cell>>)> {
+    let conn = lock_conn()?;
+    let mut stmt = conn.prepare(sql)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let column_count = columns.len();
+    let rows = stmt
+        .query_map(params, |row| {
+            (0..column_count)
+                .map(|index| row.get_ref(index).map(Cell::from))
+                .collect()
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok((columns, rows))
+}
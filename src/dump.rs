@@ -0,0 +1,678 @@
+//! SQL text dumps of the database, like the `sqlite3` shell's `.dump`.
+
+use std::ffi::{CStr, CString};
+use std::fmt::Write;
+use std::{io, ptr};
+
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{ffi, Connection, ErrorCode};
+
+use crate::query::{lock_conn, quote_identifier};
+use crate::utils;
+use crate::{sqlite_to_io, CONN};
+
+/// Dumps the database as an SQL script, handing it to `sink` in fragments of at
+/// most `max_bytes` bytes.
+///
+/// The script recreates every table with its rows, followed by the indexes,
+/// triggers and views, wrapped in a single transaction. Fragments always end
+/// between statements, so each one is a sequence of complete statements and the
+/// whole dump never has to fit in memory at once. A single statement longer than
+/// `max_bytes`, e.g. the `INSERT` of a row with a large blob, is emitted whole in
+/// its own fragment, and a warning is printed to the canister log.
+///
+/// The dump runs inside a read transaction for a consistent view, and `CONN` stays
+/// locked until it's done: `sink` must not call back into this crate.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::dump::dump_sql_chunked;
+///
+/// let mut fragments = Vec::new();
+/// dump_sql_chunked(1 << 20, |fragment| fragments.push(fragment)).unwrap();
+/// ```
+pub fn dump_sql_chunked(max_bytes: usize, sink: impl FnMut(String)) -> rusqlite::Result<()> {
+    let mut conn = lock_conn()?;
+    let tx = conn.transaction()?;
+
+    let mut out = Fragments {
+        max_bytes,
+        buffer: String::new(),
+        sink,
+    };
+    out.push("PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;\n".to_string());
+
+    let tables: Vec<(String, String)> = {
+        let mut stmt = tx.prepare(
+            "SELECT name, sql FROM sqlite_master \
+             WHERE type = 'table' AND sql IS NOT NULL AND name NOT LIKE 'sqlite_%' \
+             ORDER BY rowid;",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    for (table, sql) in &tables {
+        out.push(format!("{};\n", sql));
+        dump_rows(&tx, table, &mut out)?;
+    }
+
+    let has_sequence: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'sqlite_sequence');",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_sequence {
+        out.push("DELETE FROM sqlite_sequence;\n".to_string());
+        dump_rows(&tx, "sqlite_sequence", &mut out)?;
+    }
+
+    let mut stmt = tx.prepare(
+        "SELECT sql FROM sqlite_master \
+         WHERE type IN ('index', 'trigger', 'view') AND sql IS NOT NULL \
+         ORDER BY rowid;",
+    )?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        out.push(format!("{};\n", row.get::<_, String>(0)?));
+    }
+    drop(rows);
+    drop(stmt);
+
+    out.push("COMMIT;\n".to_string());
+    out.flush();
+    tx.commit()
+}
+
+/// Marks a chunk of [`table_export_chunks`] holding the table's schema.
+const CHUNK_SCHEMA: u8 = 0;
+
+/// Marks a chunk of [`table_export_chunks`] holding rows.
+const CHUNK_ROWS: u8 = 1;
+
+/// Type tags of the values in a rows chunk.
+const VALUE_NULL: u8 = 0;
+const VALUE_INTEGER: u8 = 1;
+const VALUE_REAL: u8 = 2;
+const VALUE_TEXT: u8 = 3;
+const VALUE_BLOB: u8 = 4;
+
+/// Exports a single table as a sequence of chunks of at most `chunk_size` bytes.
+///
+/// The first chunk holds the table's `CREATE TABLE` and `CREATE INDEX`
+/// statements, every following one a batch of rows, so the destination can
+/// create the table before any row arrives. Rows travel as data rather than SQL
+/// text. All integers are big-endian:
+/// - a schema chunk is `[u8 0][u32 count]` followed by `count` statements, each
+///   `[u32 len][len bytes of UTF-8 SQL]`;
+/// - a rows chunk is `[u8 1][u16 columns]` followed by rows of `columns` values,
+///   each a type tag and its payload: `0` for NULL, `1` and an `i64`, `2` and an
+///   `f64`, or `3` (text) and `4` (blob) followed by `[u32 len][len bytes]`.
+///
+/// Like [`dump_sql_chunked`], a row that alone exceeds `chunk_size` gets a chunk
+/// of its own. Feed the chunks, in order, to [`table_import_chunks`] on the
+/// destination, e.g. one per inter-canister call to stay below the message size
+/// limit.
+///
+/// Chunks are produced lazily and `CONN` is only locked while one is built. Rows
+/// are read in rowid order, resuming after the last exported rowid, so rows
+/// written between two chunks are exported only if their rowid is past that
+/// point: export the whole table within one message for a consistent copy.
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if `chunk_size` is `0`, the table doesn't
+/// exist or is a `WITHOUT ROWID` table. Each chunk returns an `io::Error` if its
+/// rows cannot be read.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::dump::table_export_chunks;
+///
+/// for chunk in table_export_chunks("person", 1 << 20).unwrap() {
+///     let chunk = chunk.unwrap();
+///     // ... send the chunk to the destination canister ...
+/// }
+/// ```
+pub fn table_export_chunks(
+    table: &str,
+    chunk_size: usize,
+) -> io::Result<impl Iterator<Item = io::Result<Vec<u8>>>> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk size must not be 0"));
+    }
+    let quoted = quote_identifier(table).map_err(sqlite_to_io)?;
+
+    let conn = lock_conn().map_err(sqlite_to_io)?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT sql FROM sqlite_master \
+             WHERE tbl_name = ?1 COLLATE NOCASE AND type IN ('table', 'index') AND sql IS NOT NULL \
+             ORDER BY type = 'index', rowid;",
+        )
+        .map_err(sqlite_to_io)?;
+    let statements = stmt
+        .query_map([table], |row| row.get::<_, String>(0))
+        .map_err(sqlite_to_io)?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(sqlite_to_io)?;
+    if statements.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such table: {}", table),
+        ));
+    }
+    // Fails for WITHOUT ROWID tables, which can't be paged by rowid.
+    conn.prepare(&format!("SELECT rowid FROM {} LIMIT 0;", quoted))
+        .map_err(sqlite_to_io)?;
+
+    let mut schema = vec![CHUNK_SCHEMA];
+    schema.extend_from_slice(&(statements.len() as u32).to_be_bytes());
+    for sql in &statements {
+        write_bytes(&mut schema, sql.as_bytes());
+    }
+    Ok(TableChunks {
+        table: quoted,
+        chunk_size,
+        schema: Some(schema),
+        last_rowid: None,
+        done: false,
+    })
+}
+
+/// Imports into `table` the chunks produced by [`table_export_chunks`].
+///
+/// The chunks are applied in order within a single transaction, so either all of
+/// them are imported or none is. The first chunk creates the table; to spread an
+/// import over several messages, pass each chunk on its own as it arrives, the
+/// first one first.
+///
+/// Chunks are decoded as data, so a peer can't run arbitrary SQL here: a schema
+/// chunk may only hold single `CREATE TABLE`, `CREATE INDEX` or `CREATE UNIQUE
+/// INDEX` statements, and every one of them must create `table` or an index on
+/// it. Rows are inserted into `table` with bound parameters. Their values are
+/// still taken as sent, so validate them where that matters.
+///
+/// # Errors
+///
+/// This function returns an `io::Error` if:
+/// - A chunk is malformed (`ErrorKind::InvalidData`).
+/// - A schema statement isn't allowed for `table` (`ErrorKind::PermissionDenied`).
+/// - A statement fails, e.g. because the table already exists or a row has the
+///   wrong number of columns.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::dump::table_import_chunks;
+///
+/// # let chunks: Vec<Vec<u8>> = Vec::new();
+/// table_import_chunks("person", chunks).unwrap();
+/// ```
+pub fn table_import_chunks(table: &str, chunks: impl IntoIterator<Item = impl AsRef<[u8]>>) -> io::Result<()> {
+    let quoted = quote_identifier(table).map_err(sqlite_to_io)?;
+
+    let mut conn = lock_conn().map_err(sqlite_to_io)?;
+    let tx = conn.transaction().map_err(sqlite_to_io)?;
+    for chunk in chunks {
+        let mut chunk = Decoder(chunk.as_ref());
+        match chunk.u8()? {
+            CHUNK_SCHEMA => {
+                for _ in 0..chunk.u32()? {
+                    let sql = std::str::from_utf8(chunk.bytes()?).map_err(invalid_data)?;
+                    import_schema_statement(&tx, table, sql)?;
+                }
+                if !chunk.0.is_empty() {
+                    return Err(invalid_data("trailing bytes after the schema statements"));
+                }
+            }
+            CHUNK_ROWS => {
+                let columns = chunk.u16()? as usize;
+                if columns == 0 {
+                    return Err(invalid_data("rows chunk without columns"));
+                }
+                let placeholders = vec!["?"; columns].join(", ");
+                let mut insert = tx
+                    .prepare(&format!("INSERT INTO {} VALUES ({});", quoted, placeholders))
+                    .map_err(sqlite_to_io)?;
+                while !chunk.0.is_empty() {
+                    let row = (0..columns)
+                        .map(|_| chunk.value())
+                        .collect::<io::Result<Vec<_>>>()?;
+                    insert
+                        .execute(rusqlite::params_from_iter(row))
+                        .map_err(sqlite_to_io)?;
+                }
+            }
+            kind => return Err(invalid_data(format!("unknown chunk kind {}", kind))),
+        }
+    }
+    tx.commit().map_err(sqlite_to_io)
+}
+
+/// Runs `sql` from a schema chunk if it creates `table` or one of its indexes.
+///
+/// The statement runs under an authorizer that only lets it create `table`, its
+/// indexes and its `sqlite_sequence` in the `main` schema, and read `table`'s own
+/// columns. Anything else, e.g. `CREATE TABLE ... AS SELECT` reading another
+/// table, a temporary or attached schema, triggers or views, is denied while the
+/// statement is prepared, before it runs.
+fn import_schema_statement(conn: &Connection, table: &str, sql: &str) -> io::Result<()> {
+    let denied = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("statement not allowed when importing `{}`: {}", table, sql),
+        )
+    };
+    if has_tail(conn, sql)? {
+        return Err(denied());
+    }
+
+    let owner = table.to_string();
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        let is_owner = |name: &str| name.eq_ignore_ascii_case(&owner);
+        let allowed = ctx.database_name == Some("main")
+            && match ctx.action {
+                AuthAction::CreateTable { table_name } => is_owner(table_name) || table_name == "sqlite_sequence",
+                AuthAction::CreateIndex { table_name, .. } => is_owner(table_name),
+                AuthAction::Read { table_name, .. } => is_owner(table_name) || table_name == "sqlite_master",
+                // SQLite records the new objects in `sqlite_master` and builds the
+                // new indexes itself.
+                AuthAction::Insert { table_name } | AuthAction::Update { table_name, .. } => {
+                    table_name == "sqlite_master"
+                }
+                AuthAction::Reindex { .. } => true,
+                _ => false,
+            };
+        // Functions in CHECK constraints and index expressions have no schema.
+        if allowed || matches!(ctx.action, AuthAction::Function { .. }) {
+            Authorization::Allow
+        } else {
+            Authorization::Deny
+        }
+    }));
+    let result = conn.prepare(sql).and_then(|mut stmt| stmt.execute([]));
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(err, _)) if err.code == ErrorCode::AuthorizationForStatementDenied => {
+            Err(denied())
+        }
+        Err(err) => Err(sqlite_to_io(err)),
+    }
+}
+
+/// Returns `true` if `sql` holds more than one statement, which `prepare` would
+/// silently ignore.
+fn has_tail(conn: &Connection, sql: &str) -> io::Result<bool> {
+    let c_sql = CString::new(sql).map_err(invalid_data)?;
+    let mut stmt = ptr::null_mut();
+    let mut tail = ptr::null();
+    // SAFETY: the statement is finalized right away, and `tail` points into
+    // `c_sql`, which outlives it.
+    let (rc, rest) = unsafe {
+        let rc = ffi::sqlite3_prepare_v2(conn.handle(), c_sql.as_ptr(), -1, &mut stmt, &mut tail);
+        ffi::sqlite3_finalize(stmt);
+        let rest = if tail.is_null() { "" } else { CStr::from_ptr(tail).to_str().unwrap_or("") };
+        (rc, rest.trim().to_string())
+    };
+    if rc != ffi::SQLITE_OK {
+        return Err(sqlite_to_io(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None)));
+    }
+    Ok(!rest.is_empty())
+}
+
+/// Lazy chunks returned by [`table_export_chunks`].
+struct TableChunks {
+    table: String,
+    chunk_size: usize,
+    schema: Option<Vec<u8>>,
+    last_rowid: Option<i64>,
+    done: bool,
+}
+
+impl TableChunks {
+    fn next_rows(&mut self) -> rusqlite::Result<Option<Vec<u8>>> {
+        let conn = CONN.lock().unwrap();
+        let mut stmt = match self.last_rowid {
+            Some(_) => conn.prepare(&format!(
+                "SELECT rowid, * FROM {} WHERE rowid > ?1 ORDER BY rowid;",
+                self.table
+            ))?,
+            None => conn.prepare(&format!("SELECT rowid, * FROM {} ORDER BY rowid;", self.table))?,
+        };
+        let column_count = stmt.column_count();
+        let mut rows = match self.last_rowid {
+            Some(last_rowid) => stmt.query([last_rowid])?,
+            None => stmt.query([])?,
+        };
+
+        let mut chunk = vec![CHUNK_ROWS];
+        chunk.extend_from_slice(&(column_count as u16 - 1).to_be_bytes());
+        let header_len = chunk.len();
+        let mut row_data = Vec::new();
+        while let Some(row) = rows.next()? {
+            row_data.clear();
+            for index in 1..column_count {
+                write_value(&mut row_data, row.get_ref(index)?);
+            }
+
+            // The row is read again for the next chunk.
+            if chunk.len() > header_len && chunk.len() + row_data.len() > self.chunk_size {
+                return Ok(Some(chunk));
+            }
+            chunk.extend_from_slice(&row_data);
+            self.last_rowid = Some(row.get(0)?);
+        }
+
+        self.done = true;
+        Ok(Some(chunk).filter(|chunk| chunk.len() > header_len))
+    }
+}
+
+impl Iterator for TableChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(schema) = self.schema.take() {
+            return Some(Ok(schema));
+        }
+        if self.done {
+            return None;
+        }
+        match self.next_rows() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(err) => {
+                self.done = true;
+                Some(Err(sqlite_to_io(err)))
+            }
+        }
+    }
+}
+
+/// Appends `value` to a rows chunk, see [`table_export_chunks`].
+fn write_value(out: &mut Vec<u8>, value: ValueRef) {
+    match value {
+        ValueRef::Null => out.push(VALUE_NULL),
+        ValueRef::Integer(int) => {
+            out.push(VALUE_INTEGER);
+            out.extend_from_slice(&int.to_be_bytes());
+        }
+        ValueRef::Real(real) => {
+            out.push(VALUE_REAL);
+            out.extend_from_slice(&real.to_be_bytes());
+        }
+        ValueRef::Text(text) => {
+            out.push(VALUE_TEXT);
+            write_bytes(out, text);
+        }
+        ValueRef::Blob(blob) => {
+            out.push(VALUE_BLOB);
+            write_bytes(out, blob);
+        }
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Reads the fields of a chunk from [`table_export_chunks`] front to back.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("chunk is truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        Ok(match self.u8()? {
+            VALUE_NULL => Value::Null,
+            VALUE_INTEGER => Value::Integer(self.u64()? as i64),
+            VALUE_REAL => Value::Real(f64::from_bits(self.u64()?)),
+            VALUE_TEXT => Value::Text(String::from_utf8_lossy(self.bytes()?).into_owned()),
+            VALUE_BLOB => Value::Blob(self.bytes()?.to_vec()),
+            tag => return Err(invalid_data(format!("unknown value type {}", tag))),
+        })
+    }
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Emits an `INSERT` statement for every row of `table`.
+fn dump_rows(conn: &Connection, table: &str, out: &mut Fragments<impl FnMut(String)>) -> rusqlite::Result<()> {
+    let table = quote_identifier(table)?;
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {};", table))?;
+    let column_count = stmt.column_count();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let mut sql = format!("INSERT INTO {} VALUES(", table);
+        for index in 0..column_count {
+            if index > 0 {
+                sql.push(',');
+            }
+            write_literal(&mut sql, row.get_ref(index)?);
+        }
+        sql.push_str(");\n");
+        out.push(sql);
+    }
+    Ok(())
+}
+
+/// Appends `value` as an SQL literal that reads back as the same value.
+fn write_literal(sql: &mut String, value: ValueRef) {
+    match value {
+        ValueRef::Null => sql.push_str("NULL"),
+        ValueRef::Integer(int) => write!(sql, "{}", int).unwrap(),
+        // SQLite stores NaN as NULL, so only infinities need special care.
+        ValueRef::Real(real) if real.is_infinite() => {
+            sql.push_str(if real > 0.0 { "1e999" } else { "-1e999" })
+        }
+        // `{:?}` keeps the decimal point, so whole numbers stay REAL.
+        ValueRef::Real(real) => write!(sql, "{:?}", real).unwrap(),
+        ValueRef::Text(text) => {
+            sql.push('\'');
+            sql.push_str(&String::from_utf8_lossy(text).replace('\'', "''"));
+            sql.push('\'');
+        }
+        ValueRef::Blob(blob) => {
+            sql.push_str("X'");
+            for byte in blob {
+                write!(sql, "{:02X}", byte).unwrap();
+            }
+            sql.push('\'');
+        }
+    }
+}
+
+/// Collects statements into fragments of at most `max_bytes` bytes.
+struct Fragments<F> {
+    max_bytes: usize,
+    buffer: String,
+    sink: F,
+}
+
+impl<F: FnMut(String)> Fragments<F> {
+    fn push(&mut self, statement: String) {
+        if !self.buffer.is_empty() && self.buffer.len() + statement.len() > self.max_bytes {
+            self.flush();
+        }
+        if statement.len() > self.max_bytes {
+            utils::log(format!(
+                "dump_sql_chunked: emitting a {}-byte statement over the {}-byte fragment limit",
+                statement.len(),
+                self.max_bytes
+            ));
+            (self.sink)(statement);
+        } else {
+            self.buffer.push_str(&statement);
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            (self.sink)(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_chunk(statements: &[&str]) -> Vec<u8> {
+        let mut chunk = vec![CHUNK_SCHEMA];
+        chunk.extend_from_slice(&(statements.len() as u32).to_be_bytes());
+        for sql in statements {
+            write_bytes(&mut chunk, sql.as_bytes());
+        }
+        chunk
+    }
+
+    fn rows(table: &str) -> Vec<Vec<Value>> {
+        let conn = CONN.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT * FROM {} ORDER BY rowid;", table)).unwrap();
+        let columns = stmt.column_count();
+        let rows = stmt
+            .query_map([], |row| (0..columns).map(|index| row.get(index)).collect())
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        rows
+    }
+
+    #[test]
+    fn dump_fragments_end_between_statements() {
+        let _lock = crate::test_lock();
+        utils::TEST_LOG.lock().unwrap().clear();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS fragments;
+                 CREATE TABLE fragments (id INTEGER PRIMARY KEY, data BLOB NOT NULL);
+                 WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 50)
+                 INSERT INTO fragments (data) SELECT randomblob(40) FROM n;
+                 INSERT INTO fragments (id, data) VALUES (1000, zeroblob(5000));",
+            )
+            .unwrap();
+
+        let max_bytes = 2000;
+        let mut fragments = Vec::new();
+        dump_sql_chunked(max_bytes, |fragment| fragments.push(fragment)).unwrap();
+        CONN.lock().unwrap().execute_batch("DROP TABLE fragments;").unwrap();
+
+        let oversized: Vec<&String> = fragments.iter().filter(|f| f.len() > max_bytes).collect();
+        for fragment in &fragments {
+            assert!(fragment.ends_with(";\n"));
+            let sql = CString::new(fragment.as_str()).unwrap();
+            // SAFETY: `sql` is a NUL-terminated string that outlives the call.
+            assert_eq!(unsafe { ffi::sqlite3_complete(sql.as_ptr()) }, 1, "{}", fragment);
+        }
+        // Oversized statements come whole, one per fragment, each with a warning.
+        for fragment in &oversized {
+            assert_eq!(fragment.matches(";\n").count(), 1, "{}", fragment);
+        }
+        assert!(oversized.iter().any(|f| f.starts_with("INSERT INTO \"fragments\" VALUES(1000,")));
+        assert_eq!(utils::TEST_LOG.lock().unwrap().len(), oversized.len());
+    }
+
+    #[test]
+    fn table_chunks_round_trip() {
+        let _lock = crate::test_lock();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS xfer;
+                 CREATE TABLE xfer (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, score REAL, data BLOB);
+                 CREATE INDEX xfer_name ON xfer (name);
+                 INSERT INTO xfer (name, score, data) VALUES ('a', 1.5, x'00ff'), (NULL, -2.0, NULL);
+                 INSERT INTO xfer (name, score, data) VALUES ('it''s', 1e999, zeroblob(300));",
+            )
+            .unwrap();
+        let expected = rows("xfer");
+
+        // Small chunks, so the rows are spread over several of them.
+        let chunks = table_export_chunks("XFER", 64)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert!(chunks.len() > 2);
+        CONN.lock().unwrap().execute_batch("DROP TABLE xfer;").unwrap();
+
+        table_import_chunks("xfer", &chunks).unwrap();
+        assert_eq!(rows("xfer"), expected);
+        assert!(crate::query::table_exists("xfer").unwrap());
+    }
+
+    #[test]
+    fn table_import_chunks_only_touches_the_named_table() {
+        let _lock = crate::test_lock();
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS victim;
+                 DROP TABLE IF EXISTS target;
+                 CREATE TABLE victim (secret TEXT);
+                 INSERT INTO victim VALUES ('kept');",
+            )
+            .unwrap();
+
+        for sql in [
+            "DROP TABLE victim",
+            "DELETE FROM victim",
+            "CREATE TABLE victim2 (a)",
+            "CREATE INDEX victim_secret ON victim (secret)",
+            "CREATE TRIGGER t AFTER INSERT ON victim BEGIN DELETE FROM victim; END",
+            "CREATE TABLE target (a); DROP TABLE victim",
+            "ATTACH ':memory:' AS aux",
+            "CREATE TABLE target AS SELECT secret FROM victim",
+            "CREATE TABLE temp.target (a)",
+            "CREATE TEMP TABLE target (a)",
+            "CREATE TABLE main.victim2 (a)",
+            "CREATE VIEW target AS SELECT secret FROM victim",
+        ] {
+            let err = table_import_chunks("target", [schema_chunk(&[sql])]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}: {:?}", sql, err);
+        }
+        assert_eq!(rows("victim"), vec![vec![Value::Text("kept".to_string())]]);
+        assert!(!crate::query::table_exists("target").unwrap());
+
+        let malformed: [&[u8]; 4] = [&[], &[CHUNK_SCHEMA, 0, 0], &[CHUNK_ROWS, 0, 0, VALUE_NULL], &[9]];
+        for chunk in malformed {
+            let err = table_import_chunks("target", [chunk]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", chunk);
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Custom SQL functions registered on the shared [`CONN`].
+//!
+//! Functions live on the connection, not in the database file, so they are gone
+//! after every upgrade. Register them again from `post_upgrade` (and `init`), and
+//! after restoring a backup, which reopens the connection.
+
+use std::cmp::Ordering;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::functions::FunctionFlags;
+use rusqlite::ToSql;
+
+use crate::query::quote_identifier;
+use crate::CONN;
+
+// This module shadows `rusqlite::functions`, so re-export what implementing a
+// custom function needs.
+pub use rusqlite::functions::{Aggregate, Context};
+
+/// Replaces SQLite's `random()` and `randomblob(N)` with a PRNG seeded by `seed`.
+///
+/// Both functions draw from the same stream, so a given seed and sequence of
+/// queries always produce the same values. This is meant for integration tests
+/// that assert exact query output; production code should never call it, since the
+/// values become fully predictable. Calling it again restarts the stream with the
+/// new seed, and an upgrade restores SQLite's built-in functions.
+///
+/// # Panics
+///
+/// This function panics if the functions cannot be attached to the connection.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{functions::set_deterministic_random, CONN};
+///
+/// set_deterministic_random(42);
+/// let value: i64 = CONN.lock().unwrap().query_row("SELECT random()", [], |row| row.get(0)).unwrap();
+/// ```
+pub fn set_deterministic_random(seed: u64) {
+    let state = Arc::new(Mutex::new(seed));
+    let conn = CONN.lock().unwrap();
+
+    let random_state = state.clone();
+    conn.create_scalar_function("random", 0, FunctionFlags::SQLITE_UTF8, move |_| {
+        Ok(next_random(&mut random_state.lock().unwrap()) as i64)
+    })
+    .unwrap();
+
+    conn.create_scalar_function("randomblob", 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+        // Like the built-in, anything below 1 still yields a single byte.
+        let len = ctx.get::<i64>(0)?.max(1) as usize;
+        let mut state = state.lock().unwrap();
+        let mut blob = Vec::with_capacity(len + 8);
+        while blob.len() < len {
+            blob.extend_from_slice(&next_random(&mut state).to_le_bytes());
+        }
+        blob.truncate(len);
+        Ok(blob)
+    })
+    .unwrap();
+}
+
+/// Registers a collation sequence usable as `ORDER BY name COLLATE <name>`.
+///
+/// Registering an existing name replaces its comparison function. Like every
+/// function in this module the collation is gone after an upgrade and must be
+/// registered again in `post_upgrade`, before any query or index uses it.
+///
+/// Indexes store rows in collation order. If the comparison function now orders
+/// strings differently than when an index using it was built, lookups through
+/// that index silently miss rows: use [`register_collation_and_reindex`] in that
+/// case.
+///
+/// # Errors
+///
+/// Returns an error if the collation cannot be attached to the connection.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::functions::register_collation;
+///
+/// register_collation("nocase_unicode", |a, b| a.to_lowercase().cmp(&b.to_lowercase())).unwrap();
+/// ```
+pub fn register_collation(
+    name: &str,
+    cmp: impl Fn(&str, &str) -> Ordering + Send + UnwindSafe + 'static,
+) -> rusqlite::Result<()> {
+    let conn = CONN.lock().unwrap();
+    conn.create_collation(name, cmp)
+}
+
+/// Registers a collation like [`register_collation`], then rebuilds every index
+/// that uses it with SQL `REINDEX <name>`.
+///
+/// Use it when the comparison function changed since the indexes were built, for
+/// example after an upgrade that ships a new ordering. Rebuilding reads every row
+/// of the affected tables, so plain [`register_collation`] is the better choice in
+/// `post_upgrade` when the ordering stayed the same.
+///
+/// # Errors
+///
+/// Returns an error if the collation cannot be attached to the connection or an
+/// index cannot be rebuilt.
+pub fn register_collation_and_reindex(
+    name: &str,
+    cmp: impl Fn(&str, &str) -> Ordering + Send + UnwindSafe + 'static,
+) -> rusqlite::Result<()> {
+    let sql = format!("REINDEX {};", quote_identifier(name)?);
+    let conn = CONN.lock().unwrap();
+    conn.create_collation(name, cmp)?;
+    conn.execute_batch(&sql)
+}
+
+/// Registers an aggregate function usable as `SELECT <name>(...) ... GROUP BY ...`.
+///
+/// `agg` builds a state of type `T` for every group with [`Aggregate::init`],
+/// folds each row into it with [`Aggregate::step`] and turns it into the result
+/// with [`Aggregate::finalize`]. `n_args` is the number of arguments, or `-1` for
+/// any number. Registering an existing name and argument count replaces it. Like
+/// every function in this module the aggregate is gone after an upgrade, so
+/// register it again in `post_upgrade`.
+///
+/// # Errors
+///
+/// Returns an error if the function cannot be attached to the connection.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::functions::{register_aggregate, Aggregate, Context};
+/// use ic_sqlite_features::{Result, CONN};
+///
+/// struct SumOfSquares;
+///
+/// impl Aggregate<i64, i64> for SumOfSquares {
+///     fn init(&self, _: &mut Context<'_>) -> Result<i64> {
+///         Ok(0)
+///     }
+///
+///     fn step(&self, ctx: &mut Context<'_>, sum: &mut i64) -> Result<()> {
+///         let value: i64 = ctx.get(0)?;
+///         *sum += value * value;
+///         Ok(())
+///     }
+///
+///     fn finalize(&self, _: &mut Context<'_>, sum: Option<i64>) -> Result<i64> {
+///         Ok(sum.unwrap_or(0))
+///     }
+/// }
+///
+/// register_aggregate("sum_of_squares", 1, SumOfSquares).unwrap();
+/// let total: i64 = CONN.lock().unwrap()
+///     .query_row("SELECT sum_of_squares(value) FROM t", [], |row| row.get(0))
+///     .unwrap();
+/// ```
+pub fn register_aggregate<T, R, A>(name: &str, n_args: i32, agg: A) -> rusqlite::Result<()>
+where
+    A: Aggregate<T, R> + 'static,
+    T: RefUnwindSafe + UnwindSafe,
+    R: ToSql,
+{
+    let conn = CONN.lock().unwrap();
+    conn.create_aggregate_function(name, n_args, FunctionFlags::SQLITE_UTF8, agg)
+}
+
+/// SplitMix64, small and good enough for reproducible test data.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct SumOfSquares;
+
+    impl Aggregate<i64, i64> for SumOfSquares {
+        fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<i64> {
+            Ok(0)
+        }
+
+        fn step(&self, ctx: &mut Context<'_>, sum: &mut i64) -> rusqlite::Result<()> {
+            let value: i64 = ctx.get(0)?;
+            *sum += value * value;
+            Ok(())
+        }
+
+        fn finalize(&self, _: &mut Context<'_>, sum: Option<i64>) -> rusqlite::Result<i64> {
+            Ok(sum.unwrap_or(0))
+        }
+    }
+
+    #[test]
+    fn aggregate_sums_squares_per_group() {
+        let _lock = crate::test_lock();
+        register_aggregate("sum_of_squares", 1, SumOfSquares).unwrap();
+        let conn = CONN.lock().unwrap();
+        conn.execute_batch(
+            "DROP TABLE IF EXISTS squares;
+             CREATE TABLE squares (grp TEXT, x INTEGER);
+             INSERT INTO squares VALUES ('a', 1), ('a', 2), ('a', 3), ('b', -4), ('b', 5);",
+        )
+        .unwrap();
+
+        let groups: Vec<(String, i64)> = conn
+            .prepare("SELECT grp, sum_of_squares(x) FROM squares GROUP BY grp ORDER BY grp;")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(groups, vec![("a".to_string(), 14), ("b".to_string(), 41)]);
+        conn.execute_batch("DROP TABLE squares;").unwrap();
+    }
+}
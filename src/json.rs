@@ -0,0 +1,219 @@
+//! Newline-delimited JSON (JSONL) export and import.
+//!
+//! Every row is one JSON object per line, keyed by column name, with the keys in
+//! column order. Values map as follows, in both directions:
+//! - `NULL` is `null`, `INTEGER` and `REAL` are numbers (non-finite reals are `null`).
+//! - `TEXT` is a string.
+//! - `BLOB` is an array of byte values, e.g. `[137, 80, 78, 71]`.
+//!
+//! Integers are written exactly as 64-bit numbers, not as strings. JavaScript
+//! parses numbers as doubles, so a frontend reading integers beyond ±2^53 loses
+//! precision; convert such columns to text in the query if that matters.
+//!
+//! [`value_ref_to_json`] and [`row_to_json`] implement this mapping for custom
+//! serializers.
+//!
+//! On import, booleans become `1`/`0` and nested objects or non-byte arrays are
+//! stored as their JSON text.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{ffi, Error, Params, Row};
+use serde_json::{Map, Number, Value as JsonValue};
+
+use crate::query::{lock_conn, quote_identifier};
+
+/// Runs `query` and writes each resulting row to `out` as one JSON object per line.
+///
+/// Rows are written as they are read, so the result set is never held in memory
+/// as a whole.
+///
+/// # Errors
+///
+/// Returns an error if the query fails, an `SQLITE_ERROR` failure if two columns
+/// have the same name (alias them, e.g. `SELECT a.id AS a_id, b.id AS b_id`), or
+/// an `SQLITE_IOERR` failure if writing to `out` fails.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{json::export_jsonl, params};
+///
+/// let mut out = Vec::new();
+/// export_jsonl("SELECT * FROM person WHERE age > ?1", params![18], &mut out).unwrap();
+/// ```
+pub fn export_jsonl(query: &str, params: impl Params, out: &mut impl Write) -> rusqlite::Result<()> {
+    let conn = lock_conn()?;
+    let mut stmt = conn.prepare(query)?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+    let mut rows = stmt.query(params)?;
+    while let Some(row) = rows.next()? {
+        let object = row_to_json(row, &columns)?;
+        serde_json::to_writer(&mut *out, &object).map_err(io_error)?;
+        out.write_all(b"\n").map_err(io_error)?;
+    }
+    Ok(())
+}
+
+/// Reads JSON objects, one per line, from `src` and inserts them into `table`.
+///
+/// Each object's keys name the columns to insert. All rows are inserted in a
+/// single transaction, so either every line is imported or none is. Blank lines
+/// are skipped.
+///
+/// # Returns
+///
+/// Returns the number of rows inserted.
+///
+/// # Errors
+///
+/// Returns an error if a line is not a JSON object, a key or the table name is not
+/// a valid identifier, reading `src` fails or an insert fails.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::json::import_jsonl;
+///
+/// let data = "{\"id\": 1, \"name\": \"Alice\"}\n{\"id\": 2, \"name\": \"Bob\"}\n";
+/// let inserted = import_jsonl("person", data.as_bytes()).unwrap();
+/// ```
+pub fn import_jsonl(table: &str, src: impl Read) -> rusqlite::Result<usize> {
+    let table = quote_identifier(table)?;
+
+    let mut conn = lock_conn()?;
+    let tx = conn.transaction()?;
+    let mut inserted = 0;
+    for (line_number, line) in BufReader::new(src).lines().enumerate() {
+        let line = line.map_err(io_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let object = match serde_json::from_str(&line) {
+            Ok(JsonValue::Object(object)) => object,
+            Ok(_) => return Err(format_error(line_number, "expected a JSON object".to_string())),
+            Err(err) => return Err(format_error(line_number, err.to_string())),
+        };
+        if object.is_empty() {
+            return Err(format_error(line_number, "object has no columns".to_string()));
+        }
+
+        let columns = object
+            .keys()
+            .map(|key| quote_identifier(key))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let placeholders = (1..=columns.len())
+            .map(|index| format!("?{}", index))
+            .collect::<Vec<_>>();
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+
+        let values: Vec<Value> = object.into_iter().map(|(_, value)| json_to_value(value)).collect();
+        inserted += tx.prepare_cached(&sql)?.execute(rusqlite::params_from_iter(values))?;
+    }
+    tx.commit()?;
+
+    Ok(inserted)
+}
+
+/// Converts a single SQLite value to JSON, following the mapping in the
+/// [module documentation](self).
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::{json::value_ref_to_json, types::ValueRef};
+///
+/// assert_eq!(value_ref_to_json(ValueRef::Blob(&[1, 2])), serde_json::json!([1, 2]));
+/// assert_eq!(value_ref_to_json(ValueRef::Real(f64::NAN)), serde_json::Value::Null);
+/// ```
+pub fn value_ref_to_json(value: ValueRef) -> JsonValue {
+    match value {
+        ValueRef::Null => JsonValue::Null,
+        ValueRef::Integer(int) => JsonValue::from(int),
+        ValueRef::Real(real) => Number::from_f64(real).map_or(JsonValue::Null, JsonValue::Number),
+        ValueRef::Text(text) => JsonValue::String(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(blob) => JsonValue::Array(blob.iter().map(|byte| JsonValue::from(*byte)).collect()),
+    }
+}
+
+/// Converts a row to a JSON object keyed by `columns`.
+///
+/// `columns` names the row's columns in order, as returned by
+/// `Statement::column_names`; a row with fewer columns fails with
+/// `Error::InvalidColumnIndex`. The object keeps the keys in that order. A name
+/// that appears twice fails with an `SQLITE_ERROR` failure rather than one value
+/// overwriting the other. Transform the returned object to rename or re-encode
+/// fields before serializing it.
+pub fn row_to_json(row: &Row, columns: &[String]) -> rusqlite::Result<JsonValue> {
+    let mut object = Map::new();
+    for (index, column) in columns.iter().enumerate() {
+        let value = value_ref_to_json(row.get_ref(index)?);
+        if object.insert(column.clone(), value).is_some() {
+            return Err(Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_ERROR),
+                Some(format!("duplicate column name: {}", column)),
+            ));
+        }
+    }
+    Ok(JsonValue::Object(object))
+}
+
+fn json_to_value(value: JsonValue) -> Value {
+    match value {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(boolean) => Value::Integer(boolean as i64),
+        JsonValue::Number(number) => match number.as_i64() {
+            Some(int) => Value::Integer(int),
+            None => Value::Real(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        JsonValue::String(text) => Value::Text(text),
+        JsonValue::Array(items) => {
+            let bytes: Option<Vec<u8>> = items
+                .iter()
+                .map(|item| item.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                .collect();
+            match bytes {
+                Some(bytes) => Value::Blob(bytes),
+                None => Value::Text(JsonValue::Array(items).to_string()),
+            }
+        }
+        JsonValue::Object(object) => Value::Text(JsonValue::Object(object).to_string()),
+    }
+}
+
+fn io_error(err: impl std::fmt::Display) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_IOERR), Some(err.to_string()))
+}
+
+fn format_error(line_number: usize, reason: String) -> Error {
+    Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_MISMATCH),
+        Some(format!("line {}: {}", line_number + 1, reason)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_jsonl_keeps_column_order_and_rejects_duplicates() {
+        let _lock = crate::test_lock();
+        let mut out = Vec::new();
+        export_jsonl("SELECT 2 AS b, 1 AS a, 3 AS c;", [], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "{\"b\":2,\"a\":1,\"c\":3}\n");
+
+        let mut out = Vec::new();
+        let err = export_jsonl("SELECT 1 AS id, 2 AS id;", [], &mut out).unwrap_err();
+        assert!(err.to_string().contains("duplicate column name: id"), "{}", err);
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+//! A minimal persistent key-value store on top of [`CONN`](crate::CONN).
+//!
+//! Entries live in a `_kv(key TEXT PRIMARY KEY, value BLOB)` table created by
+//! [`kv_init`], so they are backed up and restored along with the rest of the
+//! database.
+
+use rusqlite::{params, OptionalExtension};
+
+use crate::query::lock_conn;
+
+/// Creates the `_kv` table if it doesn't exist yet.
+///
+/// Call it once from `init` (calling it again is harmless) before using the other
+/// `kv_*` functions.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::kv::{kv_get, kv_init, kv_set};
+///
+/// kv_init().unwrap();
+/// kv_set("greeting", b"hello").unwrap();
+/// assert_eq!(kv_get("greeting").unwrap(), Some(b"hello".to_vec()));
+/// ```
+pub fn kv_init() -> rusqlite::Result<()> {
+    let conn = lock_conn()?;
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS _kv (key TEXT PRIMARY KEY, value BLOB);")
+}
+
+/// Returns the value stored under `key`, or `None` if there is none.
+pub fn kv_get(key: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+    let conn = lock_conn()?;
+    conn.query_row("SELECT value FROM _kv WHERE key = ?1", params![key], |row| row.get(0))
+        .optional()
+}
+
+/// Stores `value` under `key`, replacing any previous value.
+pub fn kv_set(key: &str, value: &[u8]) -> rusqlite::Result<()> {
+    let conn = lock_conn()?;
+    conn.execute(
+        "INSERT INTO _kv (key, value) VALUES (?1, ?2) \
+         ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Removes `key`, returning whether it was present.
+pub fn kv_delete(key: &str) -> rusqlite::Result<bool> {
+    let conn = lock_conn()?;
+    Ok(conn.execute("DELETE FROM _kv WHERE key = ?1", params![key])? > 0)
+}
+
+/// Returns all keys starting with `prefix`, in ascending order.
+///
+/// The lookup is a range scan over the primary key, so it doesn't touch entries
+/// outside the prefix. An empty prefix returns every key.
+pub fn kv_keys_with_prefix(prefix: &str) -> rusqlite::Result<Vec<String>> {
+    let conn = lock_conn()?;
+    let keys = match prefix_upper_bound(prefix) {
+        Some(upper) => conn
+            .prepare("SELECT key FROM _kv WHERE key >= ?1 AND key < ?2 ORDER BY key")?
+            .query_map(params![prefix, upper], |row| row.get(0))?
+            .collect(),
+        None => conn
+            .prepare("SELECT key FROM _kv WHERE key >= ?1 ORDER BY key")?
+            .query_map(params![prefix], |row| row.get(0))?
+            .collect(),
+    };
+    keys
+}
+
+/// Returns the smallest string greater than every string starting with `prefix`,
+/// or `None` if there is no such bound.
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut upper: Vec<char> = prefix.chars().collect();
+    while let Some(last) = upper.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            upper.push(next);
+            return Some(upper.into_iter().collect());
+        }
+    }
+    None
+}
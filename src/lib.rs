@@ -1,43 +1,252 @@
 pub mod vfs;
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Mutex, Arc};
 use lazy_static::lazy_static;
 use sqlite_vfs::register;
-use ic_cdk::api::stable::{stable64_size, stable64_grow, StableMemoryError};
+use ic_cdk::api::stable::StableMemoryError;
 
 pub use rusqlite::*;
 pub mod backup;
+pub mod blob;
+#[cfg(feature = "candid")]
+pub mod cell;
+pub mod dump;
+pub mod functions;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod kv;
+pub mod query;
+#[cfg(feature = "regexp")]
+pub mod regexp;
+pub mod select;
 pub(crate) mod utils;
 lazy_static! {
+    /// The connection to the database in stable memory, shared by the whole canister.
+    ///
+    /// The mutex is not reentrant: don't call any function of this crate while
+    /// holding the guard, as it would lock `CONN` again. Use
+    /// [`query::try_query`] where that can't be ruled out.
     pub static ref CONN: Arc<Mutex<Connection>> = {
         register("vfs", vfs::PagesVfs::default(), true).unwrap();
-        let conn = Connection::open_with_flags_and_vfs(
-            "main.db",
-            OpenFlags::SQLITE_OPEN_READ_WRITE
-                | OpenFlags::SQLITE_OPEN_CREATE
-                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
-            "vfs",
-        ).unwrap();
-        conn.execute_batch(
-            r#"
-            PRAGMA page_size=4096;
-            PRAGMA journal_mode=MEMORY;
-            "#,
-        ).unwrap();
+        let conn = open_connection().unwrap();
 
         return Arc::new(Mutex::new(conn));
     };
 }
 
-const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024; // 64KB
+/// Opens a connection to the database in stable memory, set up like [`CONN`].
+///
+/// The VFS must already be registered.
+pub(crate) fn open_connection() -> Result<Connection> {
+    let conn = Connection::open_with_flags_and_vfs(
+        "main.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        "vfs",
+    )?;
+    conn.execute_batch(
+        r#"
+        PRAGMA page_size=4096;
+        PRAGMA journal_mode=MEMORY;
+        "#,
+    )?;
+    if let Some(limit) = max_db_bytes() {
+        apply_max_db_bytes(&conn, limit)?;
+    }
+    Ok(conn)
+}
+
+/// Serializes the unit tests that share [`CONN`] and its stable memory.
+#[cfg(test)]
+pub(crate) fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Eagerly opens [`CONN`] and loads the schema.
+///
+/// The connection is otherwise created by the first query, which then pays for
+/// registering the VFS, applying the pragmas and reading the schema. Call this
+/// from the canister's `#[init]` and `#[post_upgrade]` to move that cost there.
+pub fn init_connection() -> Result<()> {
+    let conn = CONN.lock().unwrap();
+    conn.query_row("SELECT count(*) FROM sqlite_master;", [], |_| Ok(()))
+}
+
+/// Turns SQLite's `PRAGMA secure_delete` on or off for [`CONN`].
+///
+/// With `secure_delete` off (SQLite's default), deleted content stays in free
+/// pages until they are reused. The backup functions copy raw pages, free ones
+/// included, so such content ends up in every backup image. Enabling it makes
+/// SQLite overwrite deleted content with zeros, at the cost of extra writes.
+///
+/// It only affects future deletes: run `VACUUM` after enabling it to scrub data
+/// deleted before. The setting is not stored in the database, so apply it again
+/// after every upgrade.
+pub fn set_secure_delete(enabled: bool) -> Result<()> {
+    let conn = CONN.lock().unwrap();
+    conn.pragma_update(None, "secure_delete", enabled)
+}
+
+/// Database size limit set by [`set_max_db_bytes`], `0` if unlimited.
+static MAX_DB_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Limits the database to at most `limit` bytes of pages.
+///
+/// Backed by SQLite's `PRAGMA max_page_count`: a write that would grow the
+/// database past `limit / page_size` pages fails with `SQLITE_FULL` and the
+/// statement is rolled back, which [`backup::BackupError`] reports as
+/// `QuotaExceeded`. [`backup::db_restore`] rejects larger images the same way.
+/// SQLite never lowers the limit below the current page count, so a database
+/// already past `limit` can't grow further but keeps its data. Pass `0` to lift
+/// the limit. The limit is not stored in the database, so set it again after
+/// every upgrade.
+///
+/// # Panics
+///
+/// This function panics if the pragma cannot be applied to the connection.
+pub fn set_max_db_bytes(limit: u64) {
+    let conn = CONN.lock().unwrap();
+    apply_max_db_bytes(&conn, limit).unwrap();
+}
+
+/// Applies the limit of [`set_max_db_bytes`] to `conn` and records it.
+fn apply_max_db_bytes(conn: &Connection, limit: u64) -> Result<()> {
+    let page_size: u64 = conn.query_row("PRAGMA page_size;", [], |row| row.get::<_, i64>(0))? as u64;
+    // 4294967294 is SQLite's own default and upper bound for max_page_count.
+    let max_page_count = if limit == 0 { 4294967294 } else { (limit / page_size).max(1) };
+    conn.query_row(&format!("PRAGMA max_page_count = {};", max_page_count), [], |_| Ok(()))?;
+    MAX_DB_BYTES.store(limit, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the limit set by [`set_max_db_bytes`], if any.
+pub(crate) fn max_db_bytes() -> Option<u64> {
+    match MAX_DB_BYTES.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// Converts a rusqlite error into an `io::Error` of kind `Other`, keeping the
+/// original error (and its message) as the source.
+///
+/// Use it as `.map_err(sqlite_to_io)?` in code that has to return `io::Result`.
+/// A blanket `From` impl isn't possible, since neither type is defined here.
+pub fn sqlite_to_io(e: Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
+
+/// Stable memory usage of the database, see [`fragmentation_report`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragReport {
+    /// Bytes holding database pages (`page_count * page_size`).
+    pub used_bytes: u64,
+    /// Bytes of stable memory allocated to the canister.
+    pub allocated_bytes: u64,
+    /// Allocated bytes past the last used page, reusable by future writes.
+    pub reclaimable_bytes: u64,
+}
+
+/// Reports how much of the allocated stable memory the database still uses.
+///
+/// Stable memory never shrinks, so after a `VACUUM` the tail beyond the last page
+/// stays allocated. `reclaimable_bytes` tells how much of it a future restore or
+/// growing database can use before stable memory has to grow again.
+pub fn fragmentation_report() -> std::io::Result<FragReport> {
+    let conn = CONN.lock().unwrap();
+    let (page_count, page_size): (i64, i64) = conn
+        .query_row(
+            "SELECT page_count, page_size FROM pragma_page_count, pragma_page_size;",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .map_err(sqlite_to_io)?;
+
+    let used_bytes = page_count as u64 * page_size as u64;
+    let allocated_bytes = stable_capacity();
+    let reclaimable_bytes = allocated_bytes.saturating_sub(used_bytes + utils::header_bytes());
+
+    Ok(FragReport {
+        used_bytes,
+        allocated_bytes,
+        reclaimable_bytes,
+    })
+}
+
+/// Returns the bytes of heap memory SQLite currently has allocated.
+///
+/// This covers the page cache, prepared statements and other internal state on
+/// the canister heap, separately from the database pages in stable memory.
+pub fn memory_used() -> i64 {
+    // SAFETY: only reads SQLite's global memory statistics.
+    unsafe { ffi::sqlite3_memory_used() }
+}
+
+/// Returns the most heap memory SQLite had allocated at once, in bytes.
+///
+/// With `reset` the high-water mark restarts from the current [`memory_used`]
+/// after returning the previous value, so it can be sampled per message.
+pub fn memory_highwater(reset: bool) -> i64 {
+    // SAFETY: only reads (and optionally resets) SQLite's global memory statistics.
+    unsafe { ffi::sqlite3_memory_highwater(reset as std::os::raw::c_int) }
+}
 
 /// Gets capacity of the stable memory in bytes.
 pub fn stable_capacity() -> u64 {
-    stable64_size() << 16
+    utils::Memory::capacity(&utils::StableMemory)
 }
 
 /// Attempts to grow the memory by adding new pages.
 pub fn stable_grow_bytes(size: u64) -> Result<u64, StableMemoryError> {
-    let added_pages = (size as f64 / WASM_PAGE_SIZE_IN_BYTES as f64).ceil() as u64;
-    stable64_grow(added_pages)
-}
\ No newline at end of file
+    utils::grow_bytes(&utils::StableMemory, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupError;
+
+    #[test]
+    fn writes_past_max_db_bytes_are_rolled_back() {
+        let _lock = test_lock();
+        let count = || -> i64 {
+            CONN.lock()
+                .unwrap()
+                .query_row("SELECT count(*) FROM quota;", [], |row| row.get(0))
+                .unwrap()
+        };
+        CONN.lock()
+            .unwrap()
+            .execute_batch(
+                "DROP TABLE IF EXISTS quota;
+                 CREATE TABLE quota (data BLOB NOT NULL);
+                 INSERT INTO quota VALUES (zeroblob(100));",
+            )
+            .unwrap();
+        let page_count: u64 = CONN
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA page_count;", [], |row| row.get::<_, i64>(0))
+            .unwrap() as u64;
+        let backup = backup::db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+
+        let limit = (page_count + 4) * 4096;
+        set_max_db_bytes(limit);
+        let insert = CONN
+            .lock()
+            .unwrap()
+            .execute("INSERT INTO quota VALUES (zeroblob(100000));", [])
+            .map_err(BackupError::from);
+        set_max_db_bytes(4096);
+        let restore = backup::db_restore(&backup);
+        set_max_db_bytes(0);
+
+        assert!(matches!(insert, Err(BackupError::QuotaExceeded { limit: l }) if l == limit), "{:?}", insert);
+        assert!(matches!(restore, Err(BackupError::QuotaExceeded { limit: 4096 })), "{:?}", restore);
+        assert_eq!(count(), 1);
+        CONN.lock().unwrap().execute_batch("DROP TABLE quota;").unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+//! Small SQL helpers for the convenience APIs and schema migrations.
+
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{MutexGuard, TryLockError};
+
+use rusqlite::types::Value;
+use rusqlite::{ffi, params, Connection, Error, Params, Row, ToSql};
+
+use crate::CONN;
+
+/// Error returned by [`try_query`].
+#[derive(Debug)]
+pub enum QueryError {
+    /// `CONN` is already locked, i.e. the query was started from code that holds
+    /// the lock, such as a row callback or an open transaction.
+    Reentrant,
+    /// The query itself failed.
+    Sqlite(Error),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reentrant => f.write_str("connection is already in use by the caller"),
+            Self::Sqlite(err) => write!(f, "SQLite error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Reentrant => None,
+            Self::Sqlite(err) => Some(err),
+        }
+    }
+}
+
+impl From<Error> for QueryError {
+    fn from(err: Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+/// Last `schema_version` seen by [`schema_version`], `-1` before the first check.
+static LAST_SCHEMA_VERSION: AtomicI64 = AtomicI64::new(-1);
+
+/// Quotes `name` as an SQL identifier, e.g. `my "table"` becomes `"my ""table"""`.
+///
+/// Use this whenever a table or column name has to be spliced into SQL text,
+/// since identifiers can't be bound as parameters.
+///
+/// # Errors
+///
+/// Returns `SQLITE_MISUSE` if `name` is empty or contains a NUL byte.
+///
+/// # Example
+///
+/// ```
+/// use ic_sqlite_features::query::quote_identifier;
+///
+/// assert_eq!(quote_identifier("person").unwrap(), "\"person\"");
+/// assert!(quote_identifier("").is_err());
+/// ```
+pub fn quote_identifier(name: &str) -> rusqlite::Result<String> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(misuse(format!("invalid identifier `{}`", name.escape_debug())));
+    }
+    Ok(format!("\"{}\"", name.replace('"', "\"\"")))
+}
+
+/// Inserts a row, or updates it if it conflicts on `conflict_target`.
+///
+/// Builds and runs `INSERT INTO table (columns) VALUES (...) ON CONFLICT
+/// (conflict_target) DO UPDATE SET ...` with all identifiers quoted and all values
+/// bound. Columns outside the conflict target are overwritten with the new values;
+/// if every column is part of the target the conflicting row is left untouched.
+///
+/// # Errors
+///
+/// Returns an error if `values` and `columns` differ in length, `columns` or
+/// `conflict_target` is empty, an identifier is invalid or the statement fails.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{query::upsert, ToSql};
+///
+/// upsert("person", &["id", "name"], &["id"], &[&1 as &dyn ToSql, &"Alice"]).unwrap();
+/// ```
+pub fn upsert(
+    table: &str,
+    columns: &[&str],
+    conflict_target: &[&str],
+    values: &[&dyn ToSql],
+) -> rusqlite::Result<usize> {
+    if values.len() != columns.len() {
+        return Err(Error::InvalidParameterCount(values.len(), columns.len()));
+    }
+    if columns.is_empty() || conflict_target.is_empty() {
+        return Err(misuse("upsert needs at least one column and conflict target".to_string()));
+    }
+
+    let quoted_columns = columns
+        .iter()
+        .map(|column| quote_identifier(column))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let quoted_target = conflict_target
+        .iter()
+        .map(|column| quote_identifier(column))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let placeholders = (1..=columns.len())
+        .map(|index| format!("?{}", index))
+        .collect::<Vec<_>>();
+    let assignments = quoted_columns
+        .iter()
+        .filter(|column| !quoted_target.contains(column))
+        .map(|column| format!("{0} = excluded.{0}", column))
+        .collect::<Vec<_>>();
+    let action = if assignments.is_empty() {
+        "NOTHING".to_string()
+    } else {
+        format!("UPDATE SET {}", assignments.join(", "))
+    };
+
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO {}",
+        quote_identifier(table)?,
+        quoted_columns.join(", "),
+        placeholders.join(", "),
+        quoted_target.join(", "),
+        action
+    );
+
+    let conn = lock_conn()?;
+    conn.execute(&sql, values)
+}
+
+/// Deletes the rows of `table` matching `where_clause`, returning how many were removed.
+///
+/// Runs `DELETE FROM "table" WHERE {where_clause}` with the table name quoted and
+/// `params` bound to the placeholders of the clause. To prevent accidental
+/// full-table wipes an empty clause is rejected; use [`delete_all`] for that.
+///
+/// # Errors
+///
+/// Returns an error if `where_clause` is blank, the table name is invalid or the
+/// statement fails.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{params, query::delete_where};
+///
+/// let removed = delete_where("sessions", "expires_at < ?1", params![1_700_000_000]).unwrap();
+/// ```
+pub fn delete_where(table: &str, where_clause: &str, params: impl Params) -> rusqlite::Result<usize> {
+    if where_clause.trim().is_empty() {
+        return Err(misuse(
+            "delete_where needs a WHERE clause, use delete_all to empty a table".to_string(),
+        ));
+    }
+
+    let sql = format!("DELETE FROM {} WHERE {}", quote_identifier(table)?, where_clause);
+    let conn = lock_conn()?;
+    conn.execute(&sql, params)
+}
+
+/// Deletes every row of `table`, returning how many were removed.
+pub fn delete_all(table: &str) -> rusqlite::Result<usize> {
+    let sql = format!("DELETE FROM {}", quote_identifier(table)?);
+    let conn = lock_conn()?;
+    conn.execute(&sql, [])
+}
+
+/// Returns whether a table named `name` exists in the `main` schema.
+///
+/// The name is bound as a parameter and compared case-insensitively, like SQLite
+/// resolves identifiers.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{query::table_exists, CONN};
+///
+/// if !table_exists("person").unwrap() {
+///     CONN.lock().unwrap().execute_batch("CREATE TABLE person (id INTEGER PRIMARY KEY);").unwrap();
+/// }
+/// ```
+pub fn table_exists(name: &str) -> rusqlite::Result<bool> {
+    let conn = lock_conn()?;
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE)",
+        params![name],
+        |row| row.get(0),
+    )
+}
+
+/// Returns whether `table` exists and has a column named `column`.
+///
+/// Both names are bound as parameters of the `pragma_table_info` table-valued
+/// function, so no SQL is built from them.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{query::column_exists, CONN};
+///
+/// if !column_exists("person", "email").unwrap() {
+///     CONN.lock().unwrap().execute_batch("ALTER TABLE person ADD COLUMN email TEXT;").unwrap();
+/// }
+/// ```
+pub fn column_exists(table: &str, column: &str) -> rusqlite::Result<bool> {
+    let conn = lock_conn()?;
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2 COLLATE NOCASE)",
+        params![table, column],
+        |row| row.get(0),
+    )
+}
+
+/// Rebuilds every index of the database with SQL `REINDEX`.
+///
+/// Useful after a bulk import, or after a collation used by an index changed its
+/// ordering: such an index is only correct if it was built with the same
+/// comparison function that is registered now. See
+/// [`register_collation_and_reindex`](crate::functions::register_collation_and_reindex)
+/// to rebuild just the indexes using one collation.
+pub fn reindex() -> rusqlite::Result<()> {
+    let conn = CONN.lock().unwrap();
+    conn.execute_batch("REINDEX;")
+}
+
+/// Rebuilds the indexes of `table` with SQL `REINDEX`.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::query::reindex_table;
+///
+/// reindex_table("person").unwrap();
+/// ```
+pub fn reindex_table(table: &str) -> rusqlite::Result<()> {
+    let sql = format!("REINDEX {};", quote_identifier(table)?);
+    let conn = lock_conn()?;
+    conn.execute_batch(&sql)
+}
+
+/// Runs a query and returns every cell as a dynamically typed [`Value`].
+///
+/// Useful for generic tooling such as admin UIs or exporters that don't know the
+/// column types at compile time. Combine with [`column_names`] for the header.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{params, query::query_values, types::Value};
+///
+/// let rows = query_values("SELECT id, name FROM person WHERE age > ?1", params![18]).unwrap();
+/// for row in rows {
+///     if let Value::Text(name) = &row[1] {
+///         // ...
+///     }
+/// }
+/// ```
+pub fn query_values(sql: &str, params: impl Params) -> rusqlite::Result<Vec<Vec<Value>>> {
+    let conn = lock_conn()?;
+    let mut stmt = conn.prepare(sql)?;
+    let column_count = stmt.column_count();
+    let rows = stmt
+        .query_map(params, |row| {
+            (0..column_count).map(|index| row.get(index)).collect()
+        })?
+        .collect();
+    rows
+}
+
+/// Runs a query like [`query_values`], mapping each row with `map`, without
+/// waiting for `CONN`.
+///
+/// Every helper of this crate locks `CONN`, and the lock is not reentrant: calling
+/// one while the lock is already held by the same call stack, e.g. from a row
+/// callback or between `BEGIN` and `COMMIT` on a guard kept in scope, deadlocks
+/// or panics. This variant uses `try_lock` instead and reports the mistake as
+/// [`QueryError::Reentrant`]. Canisters run one message at a time, so the lock can
+/// only be busy because of such reentrancy.
+///
+/// # Panics
+///
+/// This function panics if `CONN` is poisoned by an earlier panic.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::query::{try_query, QueryError};
+/// use ic_sqlite_features::CONN;
+///
+/// let conn = CONN.lock().unwrap();
+/// let nested = try_query("SELECT id FROM person", [], |row| row.get::<_, i64>(0));
+/// assert!(matches!(nested, Err(QueryError::Reentrant)));
+/// ```
+pub fn try_query<T>(
+    sql: &str,
+    params: impl Params,
+    map: impl FnMut(&Row<'_>) -> rusqlite::Result<T>,
+) -> Result<Vec<T>, QueryError> {
+    let conn = match CONN.try_lock() {
+        Ok(conn) => conn,
+        Err(TryLockError::WouldBlock) => return Err(QueryError::Reentrant),
+        Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+    };
+    refresh_statement_cache(&conn)?;
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, map)?.collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Returns the names of the columns a query produces, without running it.
+pub fn column_names(sql: &str) -> rusqlite::Result<Vec<String>> {
+    let conn = lock_conn()?;
+    let stmt = conn.prepare(sql)?;
+    let names = stmt.column_names().into_iter().map(String::from).collect();
+    Ok(names)
+}
+
+/// Returns the database's `PRAGMA schema_version`, which SQLite bumps on every
+/// schema change.
+///
+/// Compare it across handlers to detect DDL. Whenever it differs from the value
+/// seen by the previous check, the connection's prepared-statement cache
+/// (`prepare_cached`) is cleared, so no cached statement outlives the schema it
+/// was compiled against.
+///
+/// The same check runs at the start of every helper of this crate that prepares
+/// statements, after [`execute_script_counted`] and after every restore. DDL run
+/// directly on [`CONN`] goes unnoticed until one of them runs: call
+/// `schema_version()` after such a migration, before using `prepare_cached` again.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::query::schema_version;
+///
+/// let before = schema_version().unwrap();
+/// // ... run migrations ...
+/// let migrated = schema_version().unwrap() != before;
+/// ```
+pub fn schema_version() -> rusqlite::Result<i32> {
+    let conn = CONN.lock().unwrap();
+    refresh_statement_cache(&conn)
+}
+
+/// Clears the prepared-statement cache of `conn` if the schema changed since the
+/// last check, returning the current schema version.
+pub(crate) fn refresh_statement_cache(conn: &Connection) -> rusqlite::Result<i32> {
+    let version: i32 = conn.query_row("PRAGMA schema_version;", [], |row| row.get(0))?;
+    let last = LAST_SCHEMA_VERSION.swap(version as i64, Ordering::Relaxed);
+    if last != version as i64 {
+        conn.flush_prepared_statement_cache();
+    }
+    Ok(version)
+}
+
+/// Locks [`CONN`] for a helper of this crate and runs [`refresh_statement_cache`]
+/// on it first.
+pub(crate) fn lock_conn() -> rusqlite::Result<MutexGuard<'static, Connection>> {
+    let conn = CONN.lock().unwrap();
+    refresh_statement_cache(&conn)?;
+    Ok(conn)
+}
+
+/// Runs a multi-statement SQL script and returns the number of rows it changed.
+///
+/// The count is the difference of SQLite's total change counter before and after
+/// the script, i.e. the rows inserted, updated or deleted by all of its
+/// statements. Handy for logging how many rows a migration touched.
+///
+/// # Errors
+///
+/// Returns the error of the first failing statement; statements before it stay
+/// applied unless the script wraps itself in a transaction.
+///
+/// The prepared-statement cache is cleared if the script changed the schema, see
+/// [`schema_version`].
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::query::execute_script_counted;
+///
+/// let touched = execute_script_counted(
+///     "UPDATE person SET age = age + 1; DELETE FROM person WHERE age > 150;",
+/// )
+/// .unwrap();
+/// ```
+pub fn execute_script_counted(sql: &str) -> rusqlite::Result<i64> {
+    let conn = CONN.lock().unwrap();
+    let before = connection_total_changes(&conn);
+    // Statements before a failing one stay applied, so check the schema either way.
+    let result = conn.execute_batch(sql);
+    refresh_statement_cache(&conn)?;
+    result?;
+    Ok(connection_total_changes(&conn) - before)
+}
+
+/// Returns the number of rows inserted, updated or deleted through `CONN` since
+/// it was opened, i.e. since the canister started or was last upgraded.
+pub fn total_changes() -> i64 {
+    let conn = CONN.lock().unwrap();
+    connection_total_changes(&conn)
+}
+
+/// Runs `f` and returns its result along with the number of rows it changed.
+///
+/// `CONN` is not locked while `f` runs, so `f` is free to use it.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::{query::changes_during, CONN};
+///
+/// let (result, changed) = changes_during(|| {
+///     CONN.lock().unwrap().execute("UPDATE person SET age = age + 1", [])
+/// });
+/// ```
+pub fn changes_during<R>(f: impl FnOnce() -> R) -> (R, i64) {
+    let before = total_changes();
+    let result = f();
+    (result, total_changes() - before)
+}
+
+fn connection_total_changes(conn: &Connection) -> i64 {
+    // SAFETY: the handle stays valid while `conn` is borrowed.
+    unsafe { ffi::sqlite3_total_changes64(conn.handle()) }
+}
+
+fn misuse(message: String) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_MISUSE), Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_query_reports_a_held_lock_as_reentrant() {
+        let _lock = crate::test_lock();
+        let select_one = || try_query("SELECT 1;", [], |row| row.get::<_, i64>(0));
+
+        let guard = CONN.lock().unwrap();
+        assert!(matches!(select_one(), Err(QueryError::Reentrant)));
+        drop(guard);
+        assert_eq!(select_one().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn helpers_and_restores_notice_schema_changes() {
+        let _lock = crate::test_lock();
+        let current = || -> i64 {
+            CONN.lock()
+                .unwrap()
+                .query_row("PRAGMA schema_version;", [], |row| row.get(0))
+                .unwrap()
+        };
+        CONN.lock()
+            .unwrap()
+            .execute_batch("DROP TABLE IF EXISTS noticed; CREATE TABLE noticed (x);")
+            .unwrap();
+        let backup = crate::backup::db_backup_on_memory(&mut CONN.lock().unwrap()).unwrap();
+
+        // DDL run directly on `CONN` is seen by the next helper.
+        assert!(table_exists("noticed").unwrap());
+        CONN.lock().unwrap().execute_batch("CREATE TABLE noticed_too (x);").unwrap();
+        assert_ne!(LAST_SCHEMA_VERSION.load(Ordering::Relaxed), current());
+        assert!(table_exists("noticed_too").unwrap());
+        assert_eq!(LAST_SCHEMA_VERSION.load(Ordering::Relaxed), current());
+
+        crate::backup::db_restore(&backup).unwrap();
+        assert!(!table_exists("noticed_too").unwrap());
+        CONN.lock().unwrap().execute_batch("DROP TABLE noticed;").unwrap();
+        crate::backup::db_restore(&backup).unwrap();
+        assert_eq!(LAST_SCHEMA_VERSION.load(Ordering::Relaxed), current());
+    }
+}
@@ -0,0 +1,71 @@
+//! `REGEXP` operator support backed by the `regex` crate.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Error;
+
+use crate::CONN;
+
+/// Upper bound on the number of compiled patterns kept around by `regexp`.
+const REGEXP_CACHE_CAPACITY: usize = 64;
+
+lazy_static! {
+    static ref REGEXP_CACHE: Mutex<HashMap<String, Arc<Regex>>> = Mutex::new(HashMap::new());
+}
+
+/// Registers the `regexp(pattern, text)` function backing SQLite's `REGEXP` operator.
+///
+/// SQLite rewrites `text REGEXP pattern` into `regexp(pattern, text)`, so after
+/// calling this `WHERE name REGEXP '^a.*'` works as expected. A `NULL` pattern or
+/// text yields `NULL`. Compiled patterns are cached, so repeated queries with the
+/// same pattern don't recompile it.
+///
+/// The function is attached to the connection only and must be registered again
+/// after every `post_upgrade` and after restoring a backup.
+///
+/// # Errors
+///
+/// Returns an error if the function cannot be attached to the connection. Invalid
+/// patterns surface as a `UserFunctionError` from the query using them.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::regexp::register_regexp;
+///
+/// register_regexp().expect("Failed to register regexp");
+/// ```
+pub fn register_regexp() -> rusqlite::Result<()> {
+    let conn = CONN.lock().unwrap();
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let pattern: Option<String> = ctx.get(0)?;
+            let text: Option<String> = ctx.get(1)?;
+            match (pattern, text) {
+                (Some(pattern), Some(text)) => Ok(Some(cached_regex(&pattern)?.is_match(&text))),
+                _ => Ok(None),
+            }
+        },
+    )
+}
+
+fn cached_regex(pattern: &str) -> rusqlite::Result<Arc<Regex>> {
+    let mut cache = REGEXP_CACHE.lock().unwrap();
+    if let Some(regex) = cache.get(pattern) {
+        return Ok(regex.clone());
+    }
+
+    let regex = Arc::new(Regex::new(pattern).map_err(|err| Error::UserFunctionError(Box::new(err)))?);
+    if cache.len() >= REGEXP_CACHE_CAPACITY {
+        cache.clear();
+    }
+    cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
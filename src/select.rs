@@ -0,0 +1,201 @@
+//! A small `SELECT` builder for dynamic queries.
+//!
+//! Table and column names are always quoted with [`quote_identifier`]; only the
+//! fragments passed to [`Select::where`] are raw SQL, with their values bound
+//! as parameters. This deliberately stays a query builder, not an ORM.
+
+use rusqlite::types::{ToSqlOutput, Value};
+use rusqlite::{params_from_iter, Row, ToSql};
+
+use crate::query::{lock_conn, quote_identifier};
+
+/// Sort direction for [`Select::order_by`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Asc,
+    Desc,
+}
+
+/// Builder for a parameterized `SELECT` statement.
+///
+/// Errors such as invalid identifiers are kept until the statement is built, so
+/// the builder can be chained freely.
+///
+/// # Example
+///
+/// ```no_run
+/// use ic_sqlite_features::params;
+/// use ic_sqlite_features::select::{Dir, Select};
+///
+/// let names: Vec<String> = Select::from("users")
+///     .columns(&["id", "name"])
+///     .r#where("age > ?", params![18])
+///     .order_by("name", Dir::Asc)
+///     .limit(50)
+///     .fetch(|row| row.get(1))
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Select {
+    table: String,
+    columns: Vec<String>,
+    conditions: Vec<String>,
+    params: Vec<Value>,
+    order_by: Vec<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    error: Option<rusqlite::Error>,
+}
+
+impl Select {
+    /// Starts a query over `table`, selecting all columns by default.
+    pub fn from(table: &str) -> Self {
+        let mut select = Select {
+            table: String::new(),
+            columns: Vec::new(),
+            conditions: Vec::new(),
+            params: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            error: None,
+        };
+        if let Some(table) = select.check(quote_identifier(table)) {
+            select.table = table;
+        }
+        select
+    }
+
+    /// Selects `columns` instead of `*`.
+    pub fn columns(mut self, columns: &[&str]) -> Self {
+        for column in columns {
+            if let Some(column) = self.check(quote_identifier(column)) {
+                self.columns.push(column);
+            }
+        }
+        self
+    }
+
+    /// Adds a raw `WHERE` condition with `?` placeholders bound to `params`.
+    ///
+    /// Several conditions are combined with `AND`. Use anonymous `?` placeholders
+    /// only, since numbered ones would refer to the wrong parameters once
+    /// conditions are combined.
+    pub fn r#where(mut self, condition: &str, params: &[&dyn ToSql]) -> Self {
+        self.conditions.push(format!("({})", condition));
+        for param in params {
+            if let Some(value) = self.check(param.to_sql().map(to_owned_value)) {
+                self.params.push(value);
+            }
+        }
+        self
+    }
+
+    /// Adds a sort key; calls are applied in order.
+    pub fn order_by(mut self, column: &str, dir: Dir) -> Self {
+        if let Some(column) = self.check(quote_identifier(column)) {
+            let dir = match dir {
+                Dir::Asc => "ASC",
+                Dir::Desc => "DESC",
+            };
+            self.order_by.push(format!("{} {}", column, dir));
+        }
+        self
+    }
+
+    /// Returns at most `limit` rows.
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first `offset` rows.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Builds the SQL statement and the parameters to bind to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error recorded while building, e.g. an invalid identifier.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ic_sqlite_features::params;
+    /// use ic_sqlite_features::select::{Dir, Select};
+    ///
+    /// let (sql, params) = Select::from("users")
+    ///     .columns(&["id", "name"])
+    ///     .r#where("age > ?", params![18])
+    ///     .order_by("name", Dir::Asc)
+    ///     .limit(50)
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(
+    ///     sql,
+    ///     r#"SELECT "id", "name" FROM "users" WHERE (age > ?) ORDER BY "name" ASC LIMIT 50"#
+    /// );
+    /// assert_eq!(params.len(), 1);
+    /// ```
+    pub fn build(self) -> rusqlite::Result<(String, Vec<Value>)> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+
+        let columns = if self.columns.is_empty() {
+            "*".to_string()
+        } else {
+            self.columns.join(", ")
+        };
+        let mut sql = format!("SELECT {} FROM {}", columns, self.table);
+        if !self.conditions.is_empty() {
+            sql.push_str(&format!(" WHERE {}", self.conditions.join(" AND ")));
+        }
+        if !self.order_by.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", self.order_by.join(", ")));
+        }
+        match (self.limit, self.offset) {
+            (Some(limit), Some(offset)) => sql.push_str(&format!(" LIMIT {} OFFSET {}", limit, offset)),
+            (Some(limit), None) => sql.push_str(&format!(" LIMIT {}", limit)),
+            (None, Some(offset)) => sql.push_str(&format!(" LIMIT -1 OFFSET {}", offset)),
+            (None, None) => {}
+        }
+
+        Ok((sql, self.params))
+    }
+
+    /// Runs the query on `CONN`, mapping every row with `map`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if building the statement, running it or `map` fails.
+    pub fn fetch<T>(self, map: impl FnMut(&Row) -> rusqlite::Result<T>) -> rusqlite::Result<Vec<T>> {
+        let (sql, params) = self.build()?;
+        let conn = lock_conn()?;
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(params), map)?.collect();
+        rows
+    }
+
+    fn check<T>(&mut self, result: rusqlite::Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.error.get_or_insert(err);
+                None
+            }
+        }
+    }
+}
+
+fn to_owned_value(output: ToSqlOutput) -> Value {
+    match output {
+        ToSqlOutput::Borrowed(value) => value.into(),
+        ToSqlOutput::Owned(value) => value,
+        ToSqlOutput::ZeroBlob(len) => Value::Blob(vec![0; len.max(0) as usize]),
+        _ => Value::Null,
+    }
+}
@@ -1,10 +1,192 @@
-use std::io;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
-use ic_cdk::api::stable::{stable64_read, stable64_size};
+#[cfg(not(test))]
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
+use ic_cdk::api::stable::StableMemoryError;
 
-pub fn read(buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
-    if stable64_size() > 0 {
-        stable64_read(offset + 8, buf);
+const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024; // 64KB
+
+/// Bytes reserved in front of the database image, see `vfs::set_vfs_header_bytes`.
+static HEADER_BYTES: AtomicU64 = AtomicU64::new(8);
+
+pub fn header_bytes() -> u64 {
+    HEADER_BYTES.load(Ordering::Relaxed)
+}
+
+pub fn set_header_bytes(n: u64) {
+    HEADER_BYTES.store(n, Ordering::Relaxed);
+}
+
+/// Memory holding the database image, laid out like stable memory.
+///
+/// The VFS and the backup functions only go through this trait, so the same code
+/// runs over [`StableMemory`] and over a [`HeapMemory`] for `backup::self_test`.
+pub trait Memory: Send + Sync {
+    /// Returns the size of the memory in 64KB WebAssembly pages.
+    fn pages(&self) -> u64;
+
+    /// Adds `pages` WebAssembly pages, returning the previous size in pages.
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError>;
+
+    fn read(&self, offset: u64, buf: &mut [u8]);
+
+    fn write(&self, offset: u64, buf: &[u8]);
+
+    /// Returns the size of the memory in bytes.
+    fn capacity(&self) -> u64 {
+        self.pages() * WASM_PAGE_SIZE_IN_BYTES
+    }
+}
+
+/// The canister's stable memory.
+pub struct StableMemory;
+
+#[cfg(not(test))]
+impl Memory for StableMemory {
+    fn pages(&self) -> u64 {
+        stable64_size()
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        stable64_grow(pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable64_read(offset, buf);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        stable64_write(offset, buf);
+    }
+}
+
+// Unit tests run natively, without the stable memory system API.
+#[cfg(test)]
+lazy_static::lazy_static! {
+    static ref TEST_STABLE_MEMORY: HeapMemory = HeapMemory::default();
+}
+
+#[cfg(test)]
+impl Memory for StableMemory {
+    fn pages(&self) -> u64 {
+        TEST_STABLE_MEMORY.pages()
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        TEST_STABLE_MEMORY.grow(pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        TEST_STABLE_MEMORY.read(offset, buf);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        TEST_STABLE_MEMORY.write(offset, buf);
+    }
+}
+
+/// A growable buffer on the heap standing in for stable memory.
+#[derive(Default)]
+pub struct HeapMemory(Mutex<Vec<u8>>);
+
+impl HeapMemory {
+    /// Drops all content, as if the memory had never been grown.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+impl Memory for HeapMemory {
+    fn pages(&self) -> u64 {
+        self.0.lock().unwrap().len() as u64 / WASM_PAGE_SIZE_IN_BYTES
+    }
+
+    fn grow(&self, pages: u64) -> Result<u64, StableMemoryError> {
+        let mut bytes = self.0.lock().unwrap();
+        let previous = bytes.len() as u64 / WASM_PAGE_SIZE_IN_BYTES;
+        bytes.resize(((previous + pages) * WASM_PAGE_SIZE_IN_BYTES) as usize, 0);
+        Ok(previous)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        let bytes = self.0.lock().unwrap();
+        buf.copy_from_slice(&bytes[offset as usize..offset as usize + buf.len()]);
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) {
+        let mut bytes = self.0.lock().unwrap();
+        bytes[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+    }
+}
+
+/// Prints `message` to the canister log.
+#[cfg(not(test))]
+pub fn log(message: String) {
+    ic_cdk::api::print(message);
+}
+
+// Unit tests run natively, where `ic_cdk::api::print` panics.
+#[cfg(test)]
+lazy_static::lazy_static! {
+    /// Messages passed to [`log`] by the unit tests.
+    pub static ref TEST_LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+#[cfg(test)]
+pub fn log(message: String) {
+    TEST_LOG.lock().unwrap().push(message);
+}
+
+/// Attempts to grow the memory by at least `size` bytes.
+pub fn grow_bytes(mem: &dyn Memory, size: u64) -> Result<u64, StableMemoryError> {
+    mem.grow(size.div_ceil(WASM_PAGE_SIZE_IN_BYTES))
+}
+
+/// Grows the memory so that an image of `len` bytes fits behind the header.
+pub fn reserve(mem: &dyn Memory, len: u64) -> Result<(), io::Error> {
+    let capacity = if mem.pages() == 0 { 0 } else { mem.capacity() - header_bytes() };
+    if len > capacity {
+        grow_bytes(mem, len - capacity).map_err(|err| io::Error::new(ErrorKind::OutOfMemory, err))?;
     }
     Result::Ok(())
-}
\ No newline at end of file
+}
+
+pub fn read(mem: &dyn Memory, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
+    if mem.pages() > 0 {
+        mem.read(offset + header_bytes(), buf);
+    }
+    Result::Ok(())
+}
+
+pub fn write(mem: &dyn Memory, buf: &[u8], offset: u64) -> Result<(), io::Error> {
+    let end = offset
+        .checked_add(buf.len() as u64)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "write past the end of the address space"))?;
+    reserve(mem, end)?;
+    if end > size(mem) {
+        set_size(mem, end);
+    }
+    mem.write(offset + header_bytes(), buf);
+    Result::Ok(())
+}
+
+pub fn size(mem: &dyn Memory) -> u64 {
+    if mem.pages() == 0 {
+        return 0;
+    }
+    if header_bytes() == 0 {
+        return mem.capacity();
+    }
+    let mut buf = [0u8; 8];
+    mem.read(0, &mut buf);
+    u64::from_be_bytes(buf)
+}
+
+pub fn set_size(mem: &dyn Memory, size: u64) {
+    if header_bytes() == 0 {
+        return;
+    }
+    mem.write(0, &size.to_be_bytes());
+}
@@ -1,17 +1,68 @@
 use std::io::{self, ErrorKind};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use ic_cdk::api::stable::{stable64_read, stable64_size, stable64_write};
 
 use sqlite_vfs::{LockKind, OpenKind, OpenOptions, Vfs};
-use crate::utils::read;
-use crate::{stable_capacity, stable_grow_bytes};
-
-const SQLITE_SIZE_IN_BYTES: u64 = 8; // 8 byte
+use crate::backup::record_page_writes;
+use crate::utils::{self, header_bytes, read, reserve, set_size, Memory, StableMemory};
+
+const SQLITE_PAGE_SIZE_IN_BYTES: u64 = 4096; // 4KB
+
+/// Sets how many bytes at the start of stable memory are reserved in front of the
+/// database image. Defaults to 8.
+///
+/// By default the first 8 bytes hold the size of the database file in bytes as a
+/// big-endian `u64`, and the SQLite file image follows at offset 8. Setting this
+/// to `0` stores a plain SQLite file image starting at offset 0, which external
+/// tools can read directly from stable memory. Without the size record the file
+/// is reported as large as all of stable memory; SQLite relies on the page count
+/// in its own header instead, so the database can't be truncated and backups
+/// still only copy `page_count` pages. Values above 8 leave the extra bytes unused.
+///
+/// This must be called before [`CONN`](crate::CONN) is first used and must stay
+/// the same across upgrades: switching it on an existing database makes its stable
+/// memory unreadable.
+///
+/// # Panics
+///
+/// Panics if `n` is between 1 and 7, too small to hold the size record.
+pub fn set_vfs_header_bytes(n: u64) {
+    assert!(
+        n == 0 || n >= 8,
+        "header must be 0 or at least 8 bytes, got {}",
+        n
+    );
+    utils::set_header_bytes(n);
+}
 
-#[derive(Default)]
 pub struct PagesVfs {
     lock_state: Arc<Mutex<LockState>>,
+    memory: Arc<dyn Memory>,
+    record_writes: bool,
+}
+
+impl Default for PagesVfs {
+    /// The VFS over stable memory used by [`CONN`](crate::CONN).
+    fn default() -> Self {
+        Self {
+            lock_state: Default::default(),
+            memory: Arc::new(StableMemory),
+            record_writes: true,
+        }
+    }
+}
+
+impl PagesVfs {
+    /// A VFS over `memory` instead of stable memory, e.g. for `backup::self_test`.
+    ///
+    /// Its writes are not counted by `backup::pages_written_since`.
+    pub(crate) fn with_memory(memory: Arc<dyn Memory>) -> Self {
+        Self {
+            lock_state: Default::default(),
+            memory,
+            record_writes: false,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -23,6 +74,8 @@ struct LockState {
 pub struct Connection {
     lock_state: Arc<Mutex<LockState>>,
     lock: LockKind,
+    memory: Arc<dyn Memory>,
+    record_writes: bool,
 }
 
 impl Vfs for PagesVfs {
@@ -47,6 +100,8 @@ impl Vfs for PagesVfs {
         Ok(Connection {
             lock_state: self.lock_state.clone(),
             lock: LockKind::None,
+            memory: self.memory.clone(),
+            record_writes: self.record_writes,
         })
     }
 
@@ -55,7 +110,7 @@ impl Vfs for PagesVfs {
     }
 
     fn exists(&self, db: &str) -> Result<bool, io::Error> {
-        Ok(db == "main.db" && Connection::size() > 0)
+        Ok(db == "main.db" && utils::size(&*self.memory) > 0)
     }
 
     fn temporary_name(&self) -> String {
@@ -78,20 +133,22 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     type WalIndex = sqlite_vfs::WalDisabled;
 
     fn size(&self) -> Result<u64, io::Error> {
-        Ok(Self::size())
+        Ok(Self::size(self))
     }
 
     fn read_exact_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
-        let _ = read(buf, offset + SQLITE_SIZE_IN_BYTES);
-        Ok(())
+        read(&*self.memory, buf, offset)
     }
 
     fn write_all_at(&mut self, buf: &[u8], offset: u64) -> Result<(), io::Error> {
         let size = offset + buf.len() as u64;
-        if size > Self::size() {
-            stable64_write(0, &size.to_be_bytes());
+        if size > Self::size(self) {
+            set_size(&*self.memory, size);
+        }
+        self.memory.write(offset + header_bytes(), buf);
+        if self.record_writes {
+            record_page_writes((buf.len() as u64).div_ceil(SQLITE_PAGE_SIZE_IN_BYTES).max(1));
         }
-        stable64_write(offset + SQLITE_SIZE_IN_BYTES, buf);
         Ok(())
     }
 
@@ -101,15 +158,10 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     }
 
     fn set_len(&mut self, size: u64) -> Result<(), io::Error> {
-        let capacity = if stable64_size() == 0 { 0 } else { stable_capacity() - SQLITE_SIZE_IN_BYTES };
+        let capacity = if self.memory.pages() == 0 { 0 } else { self.memory.capacity() - header_bytes() };
         if size > capacity {
-            stable_grow_bytes(size - capacity).map_err(|err| {
-                io::Error::new(
-                    ErrorKind::OutOfMemory,
-                    err,
-                )
-            })?;
-            stable64_write(0, &size.to_be_bytes());
+            reserve(&*self.memory, size)?;
+            set_size(&*self.memory, size);
         }
         Ok(())
     }
@@ -128,18 +180,13 @@ impl sqlite_vfs::DatabaseHandle for Connection {
     }
 
     fn wal_index(&self, _readonly: bool) -> Result<Self::WalIndex, io::Error> {
-        Ok(sqlite_vfs::WalDisabled::default())
+        Ok(sqlite_vfs::WalDisabled)
     }
 }
 
 impl Connection {
-    fn size() -> u64 {
-        if stable64_size() == 0 {
-            return 0;
-        }
-        let mut buf = [0u8; SQLITE_SIZE_IN_BYTES as usize];
-        stable64_read(0, &mut buf);
-        u64::from_be_bytes(buf)
+    fn size(&self) -> u64 {
+        utils::size(&*self.memory)
     }
 
     fn lock(&mut self, to: LockKind) -> bool {